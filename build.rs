@@ -20,4 +20,6 @@ fn main() {
 
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
+    // the runner reads the kernel's symbol table to symbolise backtraces
+    println!("cargo:rustc-env=KERNEL_PATH={}", kernel.display());
 }
\ No newline at end of file
@@ -1,64 +1,456 @@
 #[global_allocator]
-static ALLOCATOR: BumpAllocator = BumpAllocator;
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::{fmt, mem};
 use core::ptr::null_mut;
-use core::fmt::Write;
 use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
 
-use crate::serial;
+use crate::frame_allocator::{FRAME_ALLOCATOR, MAPPER};
+use log::{debug, info};
 
-pub struct BumpAllocator;
-
-pub static mut HEAP_START: usize = 0x0;
-pub static HEAP_OFFSET: AtomicUsize = AtomicUsize::new(0);
+/// Start of the virtual address range reserved for the kernel heap.
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+/// Number of bytes mapped by `init_heap`.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// The heap never grows past `HEAP_START + HEAP_MAX_SIZE`.
+pub const HEAP_MAX_SIZE: usize = 32 * 1024 * 1024; // 32 MiB
+/// Extra space mapped on top of the failed request whenever the heap grows.
+const HEAP_GROW_SIZE: usize = 64 * 1024; // 64 KiB
+const PAGE_SIZE: usize = 4096;
+
+/// End of the currently mapped part of the heap.
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+
+/// Header written at the start of every free block. Free blocks form a singly linked list
+/// sorted by address, which lets `dealloc` merge a block with its free neighbours.
+struct ListNode {
+    size: usize,
+    next: *mut ListNode,
+}
+
+impl ListNode {
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+/// The smallest block we can hand out: every block must be able to hold a `ListNode` once freed.
+const MIN_BLOCK_SIZE: usize = mem::size_of::<ListNode>();
+const MIN_BLOCK_ALIGN: usize = mem::align_of::<ListNode>();
+
+/// A first-fit allocator over an address-ordered free list. Freed blocks are coalesced with
+/// adjacent free blocks so the heap does not fragment into unusable slivers over time.
+pub struct LinkedListAllocator {
+    head: *mut ListNode,
+}
+
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator {
+    pub const fn empty() -> Self {
+        Self { head: null_mut() }
+    }
+
+    /// Hands the memory range `heap_start..heap_start + heap_size` to the allocator.
+    ///
+    /// ## Safety
+    /// The range must be valid, writable and unused, and this must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe { self.add_free_region(heap_start, heap_size) };
+    }
+
+    /// Inserts `addr..addr + size` into the free list, merging it with the free blocks
+    /// directly before and after it.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, MIN_BLOCK_ALIGN), addr);
+        assert!(size >= MIN_BLOCK_SIZE);
+
+        // Find the last free block that starts before `addr`.
+        let mut prev: *mut ListNode = null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = unsafe { (*next).next };
+        }
+
+        unsafe {
+            let node = if !prev.is_null() && (*prev).end_addr() == addr {
+                // Grow the previous block over the freed range.
+                (*prev).size += size;
+                prev
+            } else {
+                let node = addr as *mut ListNode;
+                node.write(ListNode { size, next });
+                if prev.is_null() {
+                    self.head = node;
+                } else {
+                    (*prev).next = node;
+                }
+                node
+            };
+
+            if !next.is_null() && (*node).end_addr() == next as usize {
+                // Swallow the following block as well.
+                (*node).size += (*next).size;
+                (*node).next = (*next).next;
+            }
+        }
+    }
+
+    /// Finds a free block able to hold `size` bytes aligned to `align`, removes it from the
+    /// free list and returns the leftover space on either side to the list.
+    fn allocate(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut ListNode = null_mut();
+        let mut current = self.head;
+
+        while !current.is_null() {
+            let region = unsafe { &*current };
+            if let Some(alloc_start) = Self::fit(region, size, align) {
+                let region_start = region.start_addr();
+                let region_end = region.end_addr();
+                let next = region.next;
+
+                unsafe {
+                    if prev.is_null() {
+                        self.head = next;
+                    } else {
+                        (*prev).next = next;
+                    }
+
+                    let alloc_end = alloc_start + size;
+                    if alloc_start > region_start {
+                        self.add_free_region(region_start, alloc_start - region_start);
+                    }
+                    if region_end > alloc_end {
+                        self.add_free_region(alloc_end, region_end - alloc_end);
+                    }
+                }
+                return alloc_start as *mut u8;
+            }
+            prev = current;
+            current = region.next;
+        }
+
+        null_mut()
+    }
+
+    /// Returns the start address of an allocation inside `region`, if it fits. Any space left
+    /// in front of or behind the allocation must be big enough to become a free block again.
+    fn fit(region: &ListNode, size: usize, align: usize) -> Option<usize> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        if alloc_start != region.start_addr() && alloc_start - region.start_addr() < MIN_BLOCK_SIZE {
+            alloc_start = align_up(region.start_addr() + MIN_BLOCK_SIZE, align);
+        }
+
+        let alloc_end = alloc_start.checked_add(size)?;
+        if alloc_end > region.end_addr() {
+            return None;
+        }
+
+        let excess = region.end_addr() - alloc_end;
+        if excess > 0 && excess < MIN_BLOCK_SIZE {
+            return None;
+        }
+
+        Some(alloc_start)
+    }
+
+    /// Rounds a layout up so that the resulting block can later hold a `ListNode`.
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let layout = layout
+            .align_to(MIN_BLOCK_ALIGN)
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        (layout.size().max(MIN_BLOCK_SIZE), layout.align())
+    }
+
+    /// Size of the biggest free block, i.e. the largest allocation that can succeed
+    /// without growing the heap.
+    fn largest_free_block(&self) -> usize {
+        let mut largest = 0;
+        let mut current = self.head;
+        while !current.is_null() {
+            let region = unsafe { &*current };
+            largest = largest.max(region.size);
+            current = region.next;
+        }
+        largest
+    }
+}
+
+/// Maximum number of live allocations remembered while tracking is enabled.
+const MAX_TRACKED: usize = 256;
+
+/// Heap usage counters, see `stats`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllocatorStats {
+    /// Bytes currently mapped for the heap.
+    pub heap_size: usize,
+    /// Bytes currently handed out, including padding.
+    pub bytes_in_use: usize,
+    /// Highest value `bytes_in_use` has ever reached.
+    pub peak_bytes_in_use: usize,
+    /// Number of successful allocations since boot.
+    pub allocations: usize,
+    /// Number of frees since boot.
+    pub deallocations: usize,
+    /// Number of allocations that returned null.
+    pub failed_allocations: usize,
+    /// Largest allocation that fits without growing the heap.
+    pub largest_free_block: usize,
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap size:          {} bytes", self.heap_size)?;
+        writeln!(f, "in use:             {} bytes (peak {})", self.bytes_in_use, self.peak_bytes_in_use)?;
+        writeln!(f, "allocations:        {} ({} live)", self.allocations, self.allocations - self.deallocations)?;
+        writeln!(f, "failed allocations: {}", self.failed_allocations)?;
+        write!(f, "largest free block: {} bytes", self.largest_free_block)
+    }
+}
+
+/// A live allocation recorded while tracking is enabled.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackedAllocation {
+    pub address: usize,
+    pub size: usize,
+}
+
+/// The free list plus the bookkeeping behind `stats` and `dump_allocations`. Everything in
+/// here lives in static memory, so recording an allocation never allocates.
+struct Heap {
+    free_list: LinkedListAllocator,
+    stats: AllocatorStats,
+    tracking: bool,
+    tracked: [Option<TrackedAllocation>; MAX_TRACKED],
+    /// Allocations that did not fit into `tracked`.
+    untracked: usize,
+}
+
+impl Heap {
+    const fn empty() -> Self {
+        Self {
+            free_list: LinkedListAllocator::empty(),
+            stats: AllocatorStats {
+                heap_size: 0,
+                bytes_in_use: 0,
+                peak_bytes_in_use: 0,
+                allocations: 0,
+                deallocations: 0,
+                failed_allocations: 0,
+                largest_free_block: 0,
+            },
+            tracking: false,
+            tracked: [None; MAX_TRACKED],
+            untracked: 0,
+        }
+    }
+
+    fn record_alloc(&mut self, address: usize, size: usize) {
+        let stats = &mut self.stats;
+        stats.allocations += 1;
+        stats.bytes_in_use += size;
+        stats.peak_bytes_in_use = stats.peak_bytes_in_use.max(stats.bytes_in_use);
+
+        if self.tracking {
+            match self.tracked.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => *slot = Some(TrackedAllocation { address, size }),
+                None => self.untracked += 1,
+            }
+        }
+    }
+
+    fn record_dealloc(&mut self, address: usize, size: usize) {
+        self.stats.deallocations += 1;
+        self.stats.bytes_in_use -= size;
+
+        if self.tracking {
+            if let Some(slot) = self.tracked.iter_mut().find(|slot| slot.is_some_and(|a| a.address == address)) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Wraps the heap in a spinlock. Interrupts are disabled while the lock is held, so an
+/// interrupt handler that allocates can never spin on a lock held by the code it interrupted.
+pub struct LockedHeap(Mutex<Heap>);
 
-unsafe impl GlobalAlloc for BumpAllocator {
+impl LockedHeap {
+    pub const fn empty() -> Self {
+        Self(Mutex::new(Heap::empty()))
+    }
+}
+
+unsafe impl GlobalAlloc for LockedHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // Calculate the required alignment
-        let align = layout.align();
-        let size = layout.size();
-        
-        // Get the current allocation position
-        let current_offset = HEAP_OFFSET.load(Ordering::Relaxed);
-        
-        // Calculate the aligned allocation address
-        let aligned_offset = (current_offset + align - 1) & !(align - 1);
-        
-        // Check if we have enough memory left
-        if aligned_offset + size > HEAP_SIZE {
-            writeln!(serial(), "Out of memory: offset={}, size={}", aligned_offset, size).unwrap();
-            return null_mut();
+        let (size, align) = LinkedListAllocator::size_align(layout);
+
+        let (ptr, grown) = interrupts::without_interrupts(|| {
+            let mut heap = self.0.lock();
+            let mut ptr = heap.free_list.allocate(size, align);
+            let grown = ptr.is_null() && grow(&mut heap, size + align);
+            if grown {
+                ptr = heap.free_list.allocate(size, align);
+            }
+            if ptr.is_null() {
+                heap.stats.failed_allocations += 1;
+            } else {
+                heap.record_alloc(ptr as usize, size);
+            }
+            (ptr, grown)
+        });
+
+        // Log only after the lock is released: the log sinks may allocate themselves.
+        if grown {
+            debug!("Heap grew to {} bytes", HEAP_END.load(Ordering::Relaxed) - HEAP_START);
         }
-        
-        // Update the offset atomically
-        HEAP_OFFSET.store(aligned_offset + size, Ordering::Relaxed);
-        
-        // Calculate and return the allocation address
-        let allocation_address = unsafe { HEAP_START + aligned_offset };
-        writeln!(serial(), "Allocated {} bytes at {:p} with align {}", 
-                 size, allocation_address as *mut u8, align).unwrap();
-        
-        allocation_address as *mut u8
-    }
-
-    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        writeln!(serial(), "dealloc was called at {ptr:?}").unwrap();
-    }
-}
-
-pub fn init_heap(offset: usize) {
-    unsafe {
-        HEAP_START = offset;
-        HEAP_OFFSET.store(0, Ordering::Relaxed);
-        
-        // Zero out the heap region for safety
-        for i in 0..HEAP_SIZE {
-            *((HEAP_START + i) as *mut u8) = 0;
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = LinkedListAllocator::size_align(layout);
+
+        interrupts::without_interrupts(|| {
+            let mut heap = self.0.lock();
+            unsafe { heap.free_list.add_free_region(ptr as usize, size) };
+            heap.record_dealloc(ptr as usize, size);
+        });
+    }
+}
+
+/// Returns a snapshot of the heap usage counters.
+pub fn stats() -> AllocatorStats {
+    interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+        AllocatorStats {
+            heap_size: HEAP_END.load(Ordering::Relaxed) - HEAP_START,
+            largest_free_block: heap.free_list.largest_free_block(),
+            ..heap.stats
+        }
+    })
+}
+
+/// Turns allocation tracking on or off. While it is on, every allocation is remembered until
+/// it is freed so that `dump_allocations` can list the live ones. Allocations made before
+/// tracking was switched on are not listed.
+pub fn set_tracking(enabled: bool) {
+    interrupts::without_interrupts(|| {
+        let mut heap = ALLOCATOR.0.lock();
+        heap.tracking = enabled;
+        heap.tracked = [None; MAX_TRACKED];
+        heap.untracked = 0;
+    });
+}
+
+/// Returns whether allocation tracking is switched on.
+pub fn tracking_enabled() -> bool {
+    interrupts::without_interrupts(|| ALLOCATOR.0.lock().tracking)
+}
+
+/// Writes every live tracked allocation with its size to `out`, e.g. `&mut kernel::serial()`.
+pub fn dump_allocations(out: &mut dyn fmt::Write) -> fmt::Result {
+    // Copy the table first: `out` may allocate, which would deadlock on the heap lock.
+    let (tracking, tracked, untracked) = interrupts::without_interrupts(|| {
+        let heap = ALLOCATOR.0.lock();
+        (heap.tracking, heap.tracked, heap.untracked)
+    });
+
+    if !tracking {
+        return writeln!(out, "allocation tracking is disabled");
+    }
+
+    let mut count = 0;
+    let mut total = 0;
+    for allocation in tracked.iter().flatten() {
+        writeln!(out, "{:#x}: {} bytes", allocation.address, allocation.size)?;
+        count += 1;
+        total += allocation.size;
+    }
+    writeln!(out, "{} live allocations, {} bytes", count, total)?;
+    if untracked > 0 {
+        writeln!(out, "{} more allocations did not fit in the tracking table", untracked)?;
+    }
+    Ok(())
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Maps fresh frames behind `min_size` (plus `HEAP_GROW_SIZE`) more bytes at the end of the
+/// heap and hands them to `heap`. Returns false if nothing could be mapped.
+///
+/// The page tables and frame allocator are only `try_lock`ed: if the heap runs dry while
+/// someone is already holding them (for example while mapping the APIC), growing fails
+/// instead of deadlocking.
+fn grow(heap: &mut Heap, min_size: usize) -> bool {
+    let start = HEAP_END.load(Ordering::Relaxed);
+    // Keep some headroom beyond the request, so a big allocation (like the screen's back
+    // buffer) does not leave the heap full while the page tables are locked elsewhere.
+    let size = align_up(min_size + HEAP_GROW_SIZE, PAGE_SIZE)
+        .min(HEAP_START + HEAP_MAX_SIZE - start);
+
+    let (Some(mut mapper), Some(mut frame_allocator)) = (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) else {
+        return false;
+    };
+    let (Some(mapper), Some(frame_allocator)) = (mapper.as_mut(), frame_allocator.as_mut()) else {
+        return false;
+    };
+
+    let mut end = start;
+    while end < start + size && map_heap_page(end, mapper, frame_allocator).is_ok() {
+        end += PAGE_SIZE;
+    }
+    if end == start {
+        return false;
+    }
+
+    unsafe { heap.free_list.add_free_region(start, end - start) };
+    HEAP_END.store(end, Ordering::Relaxed);
+    true
+}
+
+fn map_heap_page(
+    addr: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::containing_address(VirtAddr::new(addr as u64));
+    let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    Ok(())
+}
+
+/// Maps the first `HEAP_SIZE` bytes of the heap range with frames from the global frame
+/// allocator and hands them to the global allocator. `frame_allocator::init` must run first.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mapper = mapper.as_mut().expect("page tables not initialized");
+        let frame_allocator = frame_allocator.as_mut().expect("frame allocator not initialized");
+
+        for addr in (HEAP_START..HEAP_START + HEAP_SIZE).step_by(PAGE_SIZE) {
+            map_heap_page(addr, mapper, frame_allocator)?;
         }
-        
-        writeln!(serial(), "Heap initialized at {:p} with size {}", 
-                 HEAP_START as *mut u8, HEAP_SIZE).unwrap();
+        HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::Relaxed);
+
+        unsafe { ALLOCATOR.0.lock().free_list.init(HEAP_START, HEAP_SIZE) };
     }
-}
\ No newline at end of file
+
+    info!("Heap initialized at {:p} with size {}", HEAP_START as *mut u8, HEAP_SIZE);
+    Ok(())
+}
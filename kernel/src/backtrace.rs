@@ -0,0 +1,114 @@
+//! Stack backtraces from the frame pointer chain.
+//!
+//! The kernel is built with `-C force-frame-pointers=yes` (see `.cargo/config.toml`), so every
+//! function starts by pushing the caller's RBP and pointing RBP at it. Following that chain
+//! gives the return address of each active call.
+//!
+//! The walk only follows frames on a known stack: the kernel stack the bootloader set up, or
+//! one of the stacks [crate::gdt] maps. A corrupted RBP therefore ends the backtrace rather
+//! than faulting on unmapped memory in the middle of a panic.
+//!
+//! Each address is printed twice: as it is at run time, and relative to where the bootloader
+//! loaded the kernel (`kernel+0x...`), which is the address in the kernel ELF file. The host
+//! runner looks those up in the ELF symbol table and adds the function names.
+
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::VirtAddr;
+use crate::gdt;
+
+/// Frames printed at most, in case the chain is corrupted into a loop.
+const MAX_FRAMES: usize = 32;
+
+static KERNEL_IMAGE_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// The bootstrap processor's kernel stack, empty until [init]
+static KERNEL_STACK_BOTTOM: AtomicU64 = AtomicU64::new(0);
+static KERNEL_STACK_TOP: AtomicU64 = AtomicU64::new(0);
+
+/// Records where the kernel image was loaded, i.e. `BootInfo::kernel_image_offset`, and the
+/// kernel stack: `kernel_stack_size` bytes below `kernel_stack_top`.
+pub fn init(kernel_image_offset: u64, kernel_stack_top: VirtAddr, kernel_stack_size: u64) {
+    KERNEL_IMAGE_OFFSET.store(kernel_image_offset, Ordering::Relaxed);
+    KERNEL_STACK_BOTTOM.store(kernel_stack_top.as_u64() - kernel_stack_size, Ordering::Relaxed);
+    KERNEL_STACK_TOP.store(kernel_stack_top.as_u64(), Ordering::Relaxed);
+}
+
+/// Returns the current frame pointer.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// A printable backtrace, one return address per line.
+pub struct Backtrace {
+    /// Printed as the first frame, e.g. the faulting instruction of an exception.
+    instruction_pointer: Option<u64>,
+    frame_pointer: u64,
+}
+
+impl Backtrace {
+    /// The backtrace of the calling function.
+    #[inline(always)]
+    pub fn here() -> Self {
+        Self { instruction_pointer: None, frame_pointer: frame_pointer() }
+    }
+
+    /// The backtrace of interrupted code, starting at `instruction_pointer` and following
+    /// the chain from its RBP.
+    pub fn from_registers(instruction_pointer: u64, frame_pointer: u64) -> Self {
+        Self { instruction_pointer: Some(instruction_pointer), frame_pointer }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        let mut index = 0;
+        if let Some(instruction_pointer) = self.instruction_pointer {
+            write_frame(f, index, instruction_pointer)?;
+            index += 1;
+        }
+
+        let mut rbp = self.frame_pointer;
+        let stack = stack_containing(rbp);
+        while index < MAX_FRAMES && stack.as_ref().is_some_and(|stack| is_plausible_frame(rbp, stack)) {
+            let frame = rbp as *const u64;
+            let (caller_rbp, return_address) = unsafe { (frame.read(), frame.add(1).read()) };
+            if return_address == 0 {
+                break;
+            }
+            write_frame(f, index, return_address)?;
+            index += 1;
+
+            // Callers' frames are further up the stack; anything else means the chain is
+            // broken, or it continues on another stack (e.g. after an IST switch).
+            if caller_rbp <= rbp {
+                break;
+            }
+            rbp = caller_rbp;
+        }
+        Ok(())
+    }
+}
+
+fn write_frame(f: &mut fmt::Formatter, index: usize, address: u64) -> fmt::Result {
+    let offset = KERNEL_IMAGE_OFFSET.load(Ordering::Relaxed);
+    writeln!(f, "  #{:<2} {:#018x} (kernel+{:#x})", index, address, address.wrapping_sub(offset))
+}
+
+/// The known stack `address` lies on.
+fn stack_containing(address: u64) -> Option<Range<u64>> {
+    let kernel_stack = KERNEL_STACK_BOTTOM.load(Ordering::Relaxed)..KERNEL_STACK_TOP.load(Ordering::Relaxed);
+    if kernel_stack.contains(&address) {
+        return Some(kernel_stack);
+    }
+    gdt::cpu_stack_containing(address)
+}
+
+/// Whether a frame (the saved RBP and the return address) at `rbp` lies within `stack`.
+fn is_plausible_frame(rbp: u64, stack: &Range<u64>) -> bool {
+    rbp.is_multiple_of(8) && stack.start <= rbp && rbp.saturating_add(16) <= stack.end
+}
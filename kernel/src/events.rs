@@ -0,0 +1,59 @@
+//! The queue between interrupt handlers and the handlers of the [crate::HandlerTable].
+//!
+//! Interrupt handlers only record what happened here and return. The CPU loop takes the
+//! events out again and runs the application's handlers with interrupts enabled (see
+//! [crate::run_pending_events]), so a slow handler, such as a full-screen redraw, no longer
+//! delays or blocks other interrupts.
+//!
+//! The queue is a [RingBuffer], which allows one producer and one consumer at a time. That
+//! holds on a single CPU: the interrupt handlers are the producers and cannot interrupt each
+//! other, since interrupts stay disabled while one runs.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use pc_keyboard::DecodedKey;
+use crate::ring_buffer::RingBuffer;
+
+/// Something an interrupt handler has seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The timer ticked at least once since the last `Tick` was handled.
+    Tick,
+    /// A key was pressed or released and decoded.
+    Key(DecodedKey),
+    /// A byte was received on COM1.
+    Serial(u8),
+}
+
+static EVENTS: RingBuffer<Event, 256> = RingBuffer::new();
+/// Set while a `Tick` is queued, so a slow CPU loop gets one tick rather than a backlog.
+static TICK_PENDING: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Queues an event. Only called from interrupt handlers.
+pub(crate) fn push(event: Event) {
+    if event == Event::Tick && TICK_PENDING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    if EVENTS.push(event).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Takes the oldest event off the queue.
+pub fn pop() -> Option<Event> {
+    let event = EVENTS.pop();
+    if event == Some(Event::Tick) {
+        TICK_PENDING.store(false, Ordering::Relaxed);
+    }
+    event
+}
+
+/// Whether events are waiting to be handled.
+pub fn is_empty() -> bool {
+    EVENTS.is_empty()
+}
+
+/// Number of events lost because the queue was full.
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
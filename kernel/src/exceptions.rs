@@ -0,0 +1,286 @@
+//! Handlers for the CPU exceptions (vectors 0–31).
+//!
+//! Except for breakpoints, every architecturally defined exception enters through a small
+//! assembly stub that saves the general-purpose registers next to the CPU's interrupt stack
+//! frame and calls [exception_dispatch]. Fatal exceptions panic with an [ExceptionReport],
+//! so the decoded error code, stack frame, registers, control registers and a backtrace of
+//! the interrupted code end up on serial and on the panic screen (see
+//! [crate::set_panic_screen]).
+
+use core::fmt;
+use log::warn;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr2, Cr3, Cr4, Cr4Flags};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use crate::backtrace::Backtrace;
+use crate::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+
+/// Everything the stubs save on the stack, lowest address first.
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// The CPU's error code, or 0 for exceptions that do not push one.
+    pub error_code: u64,
+    pub frame: InterruptStackFrameValue,
+}
+
+// Each stub pushes a dummy error code if the CPU does not push one, then the vector, so
+// every exception leaves the same `ExceptionContext` on the stack. The CPU aligns the stack
+// to 16 bytes before pushing its frame, and 22 quadwords keep it aligned for the call.
+core::arch::global_asm!(
+    ".macro exception_entry vector",
+    "    push \\vector",
+    "    push rax",
+    "    push rbx",
+    "    push rcx",
+    "    push rdx",
+    "    push rsi",
+    "    push rdi",
+    "    push rbp",
+    "    push r8",
+    "    push r9",
+    "    push r10",
+    "    push r11",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    mov rdi, rsp",
+    "    cld",
+    "    call {dispatch}",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop r11",
+    "    pop r10",
+    "    pop r9",
+    "    pop r8",
+    "    pop rbp",
+    "    pop rdi",
+    "    pop rsi",
+    "    pop rdx",
+    "    pop rcx",
+    "    pop rbx",
+    "    pop rax",
+    "    add rsp, 16",
+    "    iretq",
+    ".endm",
+    ".macro exception_stub name, vector",
+    ".global \\name",
+    "\\name:",
+    "    push 0",
+    "    exception_entry \\vector",
+    ".endm",
+    ".macro exception_stub_with_error_code name, vector",
+    ".global \\name",
+    "\\name:",
+    "    exception_entry \\vector",
+    ".endm",
+    "exception_stub divide_error_stub, 0",
+    "exception_stub debug_stub, 1",
+    "exception_stub non_maskable_interrupt_stub, 2",
+    "exception_stub overflow_stub, 4",
+    "exception_stub bound_range_exceeded_stub, 5",
+    "exception_stub invalid_opcode_stub, 6",
+    "exception_stub device_not_available_stub, 7",
+    "exception_stub_with_error_code double_fault_stub, 8",
+    "exception_stub_with_error_code invalid_tss_stub, 10",
+    "exception_stub_with_error_code segment_not_present_stub, 11",
+    "exception_stub_with_error_code stack_segment_fault_stub, 12",
+    "exception_stub_with_error_code general_protection_fault_stub, 13",
+    "exception_stub_with_error_code page_fault_stub, 14",
+    "exception_stub x87_floating_point_stub, 16",
+    "exception_stub_with_error_code alignment_check_stub, 17",
+    "exception_stub machine_check_stub, 18",
+    "exception_stub simd_floating_point_stub, 19",
+    "exception_stub virtualization_stub, 20",
+    "exception_stub_with_error_code cp_protection_exception_stub, 21",
+    "exception_stub hv_injection_exception_stub, 28",
+    "exception_stub_with_error_code vmm_communication_exception_stub, 29",
+    "exception_stub_with_error_code security_exception_stub, 30",
+    dispatch = sym exception_dispatch,
+);
+
+unsafe extern "C" {
+    fn divide_error_stub();
+    fn debug_stub();
+    fn non_maskable_interrupt_stub();
+    fn overflow_stub();
+    fn bound_range_exceeded_stub();
+    fn invalid_opcode_stub();
+    fn device_not_available_stub();
+    fn double_fault_stub();
+    fn invalid_tss_stub();
+    fn segment_not_present_stub();
+    fn stack_segment_fault_stub();
+    fn general_protection_fault_stub();
+    fn page_fault_stub();
+    fn x87_floating_point_stub();
+    fn alignment_check_stub();
+    fn machine_check_stub();
+    fn simd_floating_point_stub();
+    fn virtualization_stub();
+    fn cp_protection_exception_stub();
+    fn hv_injection_exception_stub();
+    fn vmm_communication_exception_stub();
+    fn security_exception_stub();
+}
+
+/// Installs a handler for every exception in `idt`. Double faults, NMIs and machine checks
+/// switch to their own IST stacks (see [crate::gdt]), so they are handled even when the
+/// kernel stack has overflowed.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    fn address(stub: unsafe extern "C" fn()) -> VirtAddr {
+        VirtAddr::new(stub as usize as u64)
+    }
+
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    unsafe {
+        idt.divide_error.set_handler_addr(address(divide_error_stub));
+        idt.debug.set_handler_addr(address(debug_stub));
+        idt.non_maskable_interrupt.set_handler_addr(address(non_maskable_interrupt_stub))
+            .set_stack_index(NMI_IST_INDEX);
+        idt.overflow.set_handler_addr(address(overflow_stub));
+        idt.bound_range_exceeded.set_handler_addr(address(bound_range_exceeded_stub));
+        idt.invalid_opcode.set_handler_addr(address(invalid_opcode_stub));
+        idt.device_not_available.set_handler_addr(address(device_not_available_stub));
+        idt.double_fault.set_handler_addr(address(double_fault_stub))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(address(invalid_tss_stub));
+        idt.segment_not_present.set_handler_addr(address(segment_not_present_stub));
+        idt.stack_segment_fault.set_handler_addr(address(stack_segment_fault_stub));
+        idt.general_protection_fault.set_handler_addr(address(general_protection_fault_stub));
+        idt.page_fault.set_handler_addr(address(page_fault_stub));
+        idt.x87_floating_point.set_handler_addr(address(x87_floating_point_stub));
+        idt.alignment_check.set_handler_addr(address(alignment_check_stub));
+        idt.machine_check.set_handler_addr(address(machine_check_stub))
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(address(simd_floating_point_stub));
+        idt.virtualization.set_handler_addr(address(virtualization_stub));
+        idt.cp_protection_exception.set_handler_addr(address(cp_protection_exception_stub));
+        idt.hv_injection_exception.set_handler_addr(address(hv_injection_exception_stub));
+        idt.vmm_communication_exception.set_handler_addr(address(vmm_communication_exception_stub));
+        idt.security_exception.set_handler_addr(address(security_exception_stub));
+    }
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    warn!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+/// Called by the stubs. Debug exceptions are logged and execution continues; every other
+/// exception is fatal.
+extern "C" fn exception_dispatch(context: &mut ExceptionContext) {
+    if context.vector == 1 {
+        warn!("EXCEPTION: DEBUG\n{:#?}", context.frame);
+        return;
+    }
+    panic!("{}", ExceptionReport(context));
+}
+
+/// Name of an exception vector.
+pub fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "DIVIDE ERROR",
+        1 => "DEBUG",
+        2 => "NON-MASKABLE INTERRUPT",
+        3 => "BREAKPOINT",
+        4 => "OVERFLOW",
+        5 => "BOUND RANGE EXCEEDED",
+        6 => "INVALID OPCODE",
+        7 => "DEVICE NOT AVAILABLE",
+        8 => "DOUBLE FAULT",
+        10 => "INVALID TSS",
+        11 => "SEGMENT NOT PRESENT",
+        12 => "STACK-SEGMENT FAULT",
+        13 => "GENERAL PROTECTION FAULT",
+        14 => "PAGE FAULT",
+        16 => "X87 FLOATING-POINT EXCEPTION",
+        17 => "ALIGNMENT CHECK",
+        18 => "MACHINE CHECK",
+        19 => "SIMD FLOATING-POINT EXCEPTION",
+        20 => "VIRTUALIZATION EXCEPTION",
+        21 => "CONTROL PROTECTION EXCEPTION",
+        28 => "HYPERVISOR INJECTION EXCEPTION",
+        29 => "VMM COMMUNICATION EXCEPTION",
+        30 => "SECURITY EXCEPTION",
+        _ => "RESERVED",
+    }
+}
+
+/// Formats the full diagnostic dump of an exception.
+pub struct ExceptionReport<'a>(pub &'a ExceptionContext);
+
+impl fmt::Display for ExceptionReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let context = self.0;
+        let frame = &context.frame;
+
+        writeln!(f, "EXCEPTION: {} (vector {})", exception_name(context.vector), context.vector)?;
+        write!(f, "Error code: {:#x}", context.error_code)?;
+        write_decoded_error_code(f, context.vector, context.error_code)?;
+        writeln!(f)?;
+
+        writeln!(f, "RIP={:#018x} CS={:#06x} RFLAGS={:#018x}",
+                 frame.instruction_pointer.as_u64(), frame.code_segment.0, frame.cpu_flags.bits())?;
+        writeln!(f, "RSP={:#018x} SS={:#06x}", frame.stack_pointer.as_u64(), frame.stack_segment.0)?;
+
+        writeln!(f, "RAX={:#018x} RBX={:#018x} RCX={:#018x}", context.rax, context.rbx, context.rcx)?;
+        writeln!(f, "RDX={:#018x} RSI={:#018x} RDI={:#018x}", context.rdx, context.rsi, context.rdi)?;
+        writeln!(f, "RBP={:#018x} R8 ={:#018x} R9 ={:#018x}", context.rbp, context.r8, context.r9)?;
+        writeln!(f, "R10={:#018x} R11={:#018x} R12={:#018x}", context.r10, context.r11, context.r12)?;
+        writeln!(f, "R13={:#018x} R14={:#018x} R15={:#018x}", context.r13, context.r14, context.r15)?;
+
+        let (cr3_frame, cr3_flags) = Cr3::read_raw();
+        writeln!(f, "CR0={:#018x} {:?}", Cr0::read_raw(), Cr0Flags::from_bits_truncate(Cr0::read_raw()))?;
+        writeln!(f, "CR2={:#018x}", Cr2::read_raw())?;
+        writeln!(f, "CR3={:#018x}", cr3_frame.start_address().as_u64() | u64::from(cr3_flags))?;
+        writeln!(f, "CR4={:#018x} {:?}", Cr4::read_raw(), Cr4Flags::from_bits_truncate(Cr4::read_raw()))?;
+        write!(f, "{}", Backtrace::from_registers(frame.instruction_pointer.as_u64(), context.rbp))
+    }
+}
+
+/// Explains the error code of the exceptions that push a meaningful one.
+fn write_decoded_error_code(f: &mut fmt::Formatter, vector: u64, error_code: u64) -> fmt::Result {
+    match vector {
+        14 => write!(f, " {:?}, accessed address {:#x}",
+                     PageFaultErrorCode::from_bits_truncate(error_code), Cr2::read_raw()),
+        10..=13 if error_code != 0 => {
+            let table = match (error_code >> 1) & 0b11 {
+                0b00 => "GDT",
+                0b10 => "LDT",
+                _ => "IDT",
+            };
+            let external = if error_code & 1 != 0 { ", external event" } else { "" };
+            write!(f, " (selector index {} in the {}{})", (error_code >> 3) & 0x1FFF, table, external)
+        }
+        21 => {
+            let cause = match error_code & 0x7FFF {
+                1 => "near RET",
+                2 => "far RET/IRET",
+                3 => "missing ENDBRANCH",
+                4 => "RSTORSSP",
+                5 => "SETSSBSY",
+                _ => "unknown",
+            };
+            write!(f, " ({})", cause)
+        }
+        _ => Ok(()),
+    }
+}
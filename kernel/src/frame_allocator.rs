@@ -1,43 +1,238 @@
+use core::fmt;
 use bootloader_api::info::MemoryRegionKind::Usable;
 use bootloader_api::info::MemoryRegions;
+use spin::Mutex;
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// The kernel's page tables, set up by `init`.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+/// The kernel's physical frame allocator, set up by `init`. The heap takes its frames from
+/// here too, so heap pages and page-table frames can never overlap.
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// Frame counts reported by `BitmapFrameAllocator::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} frames total, {} used, {} free ({} KiB free)",
+               self.total, self.used, self.free, self.free * FRAME_SIZE as usize / 1024)
+    }
+}
+
+/// A physical frame allocator keeping one bit per 4 KiB frame (set = in use).
+///
+/// The bitmaps live in the first usable region big enough to hold them and are reached
+/// through the physical memory offset mapping; their frames are marked as used.
+/// A summary bitmap has one bit per bitmap word that still has a free frame, so allocation
+/// finds a free frame from the summary and the word it points at instead of scanning the
+/// bitmap, and freed frames become available again at once. A third bitmap records which
+/// frames are usable memory, so only those can be freed.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// One bit per frame, set = usable memory this allocator hands out
+    usable: &'static mut [u64],
+    /// One bit per word of `bitmap`, set = the word has a free frame
+    summary: &'static mut [u64],
+    total_frames: usize,
+    free_frames: usize,
+    /// Every summary word below this one is zero
+    next_summary: usize,
 }
 
-impl BootInfoFrameAllocator {
-    pub fn new(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+impl BitmapFrameAllocator {
+    /// Builds the bitmaps from the bootloader's memory map.
+    ///
+    /// ## Safety
+    /// `physical_memory_offset` must be where the complete physical memory is mapped, and
+    /// the usable regions of `memory_map` must really be unused.
+    pub unsafe fn new(memory_map: &MemoryRegions, physical_memory_offset: VirtAddr) -> Self {
+        let frame_count = memory_map.iter()
+            .filter(|region| region.kind == Usable)
+            .map(|region| region.end.div_ceil(FRAME_SIZE))
+            .max()
+            .unwrap_or(0) as usize;
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let summary_words = words.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = ((2 * words + summary_words) * size_of::<u64>()) as u64;
+
+        let bitmap_start = memory_map.iter()
+            .filter(|region| region.kind == Usable)
+            .find(|region| align_up(region.start, FRAME_SIZE) + bitmap_bytes <= region.end)
+            .map(|region| align_up(region.start, FRAME_SIZE))
+            .expect("no usable region can hold the frame bitmap");
+
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let all_words = unsafe { core::slice::from_raw_parts_mut(bitmap_ptr, 2 * words + summary_words) };
+        let (bitmap, rest) = all_words.split_at_mut(words);
+        let (usable, summary) = rest.split_at_mut(words);
+        bitmap.fill(u64::MAX);
+        usable.fill(0);
+        summary.fill(0);
+
+        let mut allocator = Self {
+            bitmap,
+            usable,
+            summary,
+            total_frames: 0,
+            free_frames: 0,
+            next_summary: 0,
+        };
+
+        for frame in usable_frames(memory_map) {
+            let index = frame_index(frame);
+            allocator.usable[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+            allocator.set_free(index, true);
+            allocator.total_frames += 1;
+            allocator.free_frames += 1;
+        }
+
+        // Never hand out frame 0, and keep the frames holding the bitmaps for ourselves.
+        allocator.reserve(0);
+        let bitmap_frames = PhysFrame::range(
+            PhysFrame::containing_address(PhysAddr::new(bitmap_start)),
+            PhysFrame::containing_address(PhysAddr::new(align_up(bitmap_start + bitmap_bytes, FRAME_SIZE))),
+        );
+        for frame in bitmap_frames {
+            allocator.reserve(frame_index(frame));
         }
+
+        allocator
+    }
+
+    /// Returns the current frame counts.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total_frames,
+            free: self.free_frames,
+            used: self.total_frames - self.free_frames,
+        }
+    }
+
+    /// Allocates a frame that lies entirely below `limit`, for hardware that can only reach
+    /// low memory. Allocate such frames early, before other allocations use up low memory.
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame<Size4KiB>> {
+        let index = (0..(limit.as_u64() / FRAME_SIZE) as usize).find(|&index| self.is_free(index))?;
+        self.claim(index);
+        Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)))
     }
-    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
 
-        let usable_regions = regions.filter(|region| region.kind == Usable);
-        let address_ranges = usable_regions.map(|region| region.start..region.end);
-        let frame_addresses = address_ranges.flat_map(|region| region.step_by(4096));
+    fn is_free(&self, index: usize) -> bool {
+        self.bitmap.get(index / BITS_PER_WORD)
+            .is_some_and(|word| word & (1 << (index % BITS_PER_WORD)) == 0)
+    }
+
+    fn is_usable(&self, index: usize) -> bool {
+        self.usable.get(index / BITS_PER_WORD)
+            .is_some_and(|word| word & (1 << (index % BITS_PER_WORD)) != 0)
+    }
 
-        frame_addresses.map(|address| PhysFrame::containing_address(PhysAddr::new(address)))
+    fn set_free(&mut self, index: usize, free: bool) {
+        let word_index = index / BITS_PER_WORD;
+        let word = &mut self.bitmap[word_index];
+        let bit = 1 << (index % BITS_PER_WORD);
+        if free {
+            *word &= !bit;
+        } else {
+            *word |= bit;
+        }
+
+        let has_free = *word != u64::MAX;
+        let summary_index = word_index / BITS_PER_WORD;
+        let summary_bit = 1 << (word_index % BITS_PER_WORD);
+        if has_free {
+            self.summary[summary_index] |= summary_bit;
+            self.next_summary = self.next_summary.min(summary_index);
+        } else {
+            self.summary[summary_index] &= !summary_bit;
+        }
+    }
+
+    /// Marks a frame as used if it is currently free.
+    fn claim(&mut self, index: usize) {
+        if self.is_free(index) {
+            self.set_free(index, false);
+            self.free_frames -= 1;
+        }
+    }
+
+    /// Claims a frame for good: it can never be freed.
+    fn reserve(&mut self, index: usize) {
+        self.claim(index);
+        if let Some(word) = self.usable.get_mut(index / BITS_PER_WORD) {
+            *word &= !(1 << (index % BITS_PER_WORD));
+        }
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        // Only skips summary words that became zero since `next_summary` was last lowered
+        let summary_index = (self.next_summary..self.summary.len()).find(|&i| self.summary[i] != 0)?;
+        self.next_summary = summary_index;
+        let word_index = summary_index * BITS_PER_WORD + self.summary[summary_index].trailing_zeros() as usize;
+        let index = word_index * BITS_PER_WORD + self.bitmap[word_index].trailing_ones() as usize;
+        self.set_free(index, false);
+        self.free_frames -= 1;
+
+        Some(PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = frame_index(frame);
+        assert!(self.is_usable(index), "frame {:?} is not usable memory handed out by this allocator", frame);
+        assert!(!self.is_free(index), "double free of frame {:?}", frame);
+
+        self.set_free(index, true);
+        self.free_frames += 1;
     }
 }
 
-pub fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+/// Returns the frame statistics of the global frame allocator, if it has been set up.
+pub fn stats() -> Option<FrameStats> {
+    FRAME_ALLOCATOR.lock().as_ref().map(BitmapFrameAllocator::stats)
+}
+
+fn usable_frames(memory_map: &MemoryRegions) -> impl Iterator<Item = PhysFrame> + '_ {
+    let regions = memory_map.iter();
+
+    let usable_regions = regions.filter(|region| region.kind == Usable);
+    // Only whole frames: round the start up and the end down.
+    let address_ranges = usable_regions.map(|region| align_up(region.start, FRAME_SIZE)..region.end & !(FRAME_SIZE - 1));
+    let frame_addresses = address_ranges.flat_map(|region| region.step_by(FRAME_SIZE as usize));
+
+    frame_addresses.map(|address| PhysFrame::containing_address(PhysAddr::new(address)))
+}
+
+fn frame_index(frame: PhysFrame) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Sets up the global `MAPPER` and `FRAME_ALLOCATOR`.
+pub fn init(physical_memory_offset: VirtAddr, memory_map: &MemoryRegions) {
     let level4_table = active_level4_table(physical_memory_offset);
-    unsafe { OffsetPageTable::new(level4_table, physical_memory_offset) }
+    *MAPPER.lock() = Some(unsafe { OffsetPageTable::new(level4_table, physical_memory_offset) });
+    *FRAME_ALLOCATOR.lock() = Some(unsafe { BitmapFrameAllocator::new(memory_map, physical_memory_offset) });
 }
 
 fn active_level4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
@@ -48,4 +243,4 @@ fn active_level4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTab
     let page_table_pointer: *mut PageTable = virtual_address.as_mut_ptr();
 
     unsafe { &mut *page_table_pointer }
-}
\ No newline at end of file
+}
@@ -1,42 +1,61 @@
-use core::ptr::addr_of;
-use lazy_static::lazy_static;
+use alloc::boxed::Box;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{debug, info};
 use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+const IST_STACK_COUNT: u64 = 3;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Each CPU's stacks live in their own range from here on, each one preceded by an unmapped
+/// guard page: the IST stacks, then (on the application processors) the kernel stack.
+const CPU_STACKS_START: u64 = 0x_5555_5555_0000;
+const IST_STACK_SIZE: u64 = 8 * Size4KiB::SIZE;
+/// Size of the kernel stack of each application processor. The bootstrap processor uses the
+/// one the bootloader set up.
+pub const AP_STACK_SIZE: u64 = 16 * Size4KiB::SIZE;
+const CPU_STACKS_SIZE: u64 = IST_STACK_COUNT * (Size4KiB::SIZE + IST_STACK_SIZE) + Size4KiB::SIZE + AP_STACK_SIZE;
 
-            let stack_start = VirtAddr::from_ptr( addr_of!(STACK) );
-            stack_start + STACK_SIZE as u64 // stack_end
-        };
-        tss
-    };
+/// Returns the (exclusive) top of the IST stack used for `index` on CPU `cpu`.
+const fn ist_stack_top(cpu: usize, index: u16) -> u64 {
+    CPU_STACKS_START + cpu as u64 * CPU_STACKS_SIZE + (index as u64 + 1) * (Size4KiB::SIZE + IST_STACK_SIZE)
+}
 
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt: GlobalDescriptorTable = GlobalDescriptorTable::new();
+/// Returns the (exclusive) top of the kernel stack of application processor `cpu`.
+const fn ap_stack_top(cpu: usize) -> u64 {
+    CPU_STACKS_START + (cpu as u64 + 1) * CPU_STACKS_SIZE
+}
 
-        let code_selector = gdt.append(Descriptor::kernel_code_segment());
-        let data_selector = gdt.append(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+/// Number of CPUs whose IST stacks, and whose kernel stacks, have been mapped. CPUs are set
+/// up in the order of their index, so these are the CPUs below the count.
+static IST_STACKS_MAPPED: AtomicUsize = AtomicUsize::new(0);
+static AP_STACKS_MAPPED: AtomicUsize = AtomicUsize::new(0);
 
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                data_selector,
-                tss_selector,
-            },
-        )
-    };
+/// The mapped IST or application processor kernel stack `address` lies on, from its bottom
+/// to its top. Does not take any lock, so backtraces can use it in a panic.
+pub fn cpu_stack_containing(address: u64) -> Option<Range<u64>> {
+    let cpu = (address.checked_sub(CPU_STACKS_START)? / CPU_STACKS_SIZE) as usize;
+    let mut stacks = (0..IST_STACK_COUNT as u16)
+        .filter(|_| cpu < IST_STACKS_MAPPED.load(Ordering::Relaxed))
+        .map(|index| ist_stack_top(cpu, index) - IST_STACK_SIZE..ist_stack_top(cpu, index))
+        // The bootstrap processor runs on the stack the bootloader set up
+        .chain((cpu != 0 && cpu < AP_STACKS_MAPPED.load(Ordering::Relaxed))
+            .then(|| ap_stack_top(cpu) - AP_STACK_SIZE..ap_stack_top(cpu)));
+    stacks.find(|stack| stack.contains(&address))
+}
+
+/// A CPU's GDT, pointing at its own TSS and IST stacks.
+pub struct CpuTables {
+    gdt: GlobalDescriptorTable,
+    selectors: Selectors,
 }
 
 struct Selectors {
@@ -45,16 +64,122 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
-pub fn init() {
-    GDT.0.load();
-    unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        SS::set_reg(GDT.1.data_selector);
-        DS::set_reg(GDT.1.data_selector);
-        ES::set_reg(GDT.1.data_selector);
-        FS::set_reg(GDT.1.data_selector);
-        GS::set_reg(GDT.1.data_selector);
+impl CpuTables {
+    /// Builds the TSS and GDT of CPU `cpu` (0 being the bootstrap processor), pointing at the
+    /// IST stacks [map_ist_stacks] maps. Needs the heap.
+    pub fn new(cpu: usize) -> &'static CpuTables {
+        let mut tss = TaskStateSegment::new();
+        for index in [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX] {
+            tss.interrupt_stack_table[index as usize] = VirtAddr::new(ist_stack_top(cpu, index));
+        }
+        let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
 
-        load_tss(GDT.1.tss_selector)
+        let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.append(Descriptor::kernel_code_segment());
+        let data_selector = gdt.append(Descriptor::kernel_data_segment());
+        let tss_selector = gdt.append(Descriptor::tss_segment(tss));
+
+        Box::leak(Box::new(CpuTables {
+            gdt,
+            selectors: Selectors { code_selector, data_selector, tss_selector },
+        }))
     }
-}
\ No newline at end of file
+
+    /// Loads the GDT and TSS on the running CPU. Reloading GS clears the GS base, so set up the
+    /// per-CPU data afterwards (see [crate::smp]).
+    pub fn load(&'static self) {
+        self.gdt.load();
+        unsafe {
+            CS::set_reg(self.selectors.code_selector);
+            SS::set_reg(self.selectors.data_selector);
+            DS::set_reg(self.selectors.data_selector);
+            ES::set_reg(self.selectors.data_selector);
+            FS::set_reg(self.selectors.data_selector);
+            GS::set_reg(self.selectors.data_selector);
+
+            load_tss(self.selectors.tss_selector)
+        }
+    }
+}
+
+/// Maps the IST stacks of the bootstrap processor, leaving a guard page below each of them,
+/// and loads its GDT and TSS.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    map_ist_stacks(0, mapper, frame_allocator)?;
+    CpuTables::new(0).load();
+    Ok(())
+}
+
+/// Maps the IST stacks of CPU `cpu`.
+pub fn map_ist_stacks(
+    cpu: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    for index in [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX, MACHINE_CHECK_IST_INDEX] {
+        let top = VirtAddr::new(ist_stack_top(cpu, index));
+        map_stack(top, IST_STACK_SIZE, mapper, frame_allocator)?;
+        debug!("CPU {} IST stack {} at {:#x}-{:#x}", cpu, index, top - IST_STACK_SIZE, top);
+    }
+    IST_STACKS_MAPPED.fetch_max(cpu + 1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Maps the kernel stack of application processor `cpu` and returns its top.
+pub fn map_ap_stack(
+    cpu: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let top = VirtAddr::new(ap_stack_top(cpu));
+    map_stack(top, AP_STACK_SIZE, mapper, frame_allocator)?;
+    AP_STACKS_MAPPED.fetch_max(cpu + 1, Ordering::Relaxed);
+    Ok(top)
+}
+
+/// Maps `size` bytes of fresh frames below `top`. The page below stays unmapped as a guard.
+fn map_stack(
+    top: VirtAddr,
+    size: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let pages = Page::range(Page::containing_address(top - size), Page::containing_address(top));
+    for page in pages {
+        let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(())
+}
+
+/// Returns the current stack pointer. Called first thing on entry, it tells where the top of
+/// the kernel stack is (see [guard_kernel_stack]).
+#[inline(always)]
+pub fn stack_pointer() -> VirtAddr {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    VirtAddr::new(rsp)
+}
+
+/// Makes sure the page below the kernel stack is unmapped, so that overflowing the stack
+/// page faults (and, since the fault cannot be pushed on the stack, double faults onto its
+/// IST stack) instead of silently overwriting whatever lies below.
+///
+/// `entry_stack_pointer` is the stack pointer on entry to the kernel, `stack_size` the
+/// `kernel_stack_size` from the bootloader config.
+pub fn guard_kernel_stack(entry_stack_pointer: VirtAddr, stack_size: u64, mapper: &mut impl Mapper<Size4KiB>) {
+    let stack_bottom = entry_stack_pointer.align_up(Size4KiB::SIZE) - stack_size;
+    let guard_page: Page<Size4KiB> = Page::containing_address(stack_bottom - 1u64);
+
+    match mapper.unmap(guard_page) {
+        Ok((_, flush)) => {
+            flush.flush();
+            info!("Unmapped guard page {:?} below the kernel stack", guard_page.start_address());
+        }
+        Err(_) => debug!("Kernel stack guard page {:?} is not mapped", guard_page.start_address()),
+    }
+}
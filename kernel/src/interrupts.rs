@@ -1,44 +1,174 @@
-use core::fmt::Write;
+//! Interrupt controller setup and the interrupt handlers that are not CPU exceptions.
+//!
+//! [init_interrupt_controller] sets up the local APIC (in x2APIC mode where the CPU supports
+//! it) and the IO APICs of the MADT, or falls back to the 8259 PIC and the PIT. The LAPIC timer
+//! and the TSC are calibrated against the PIT. The timer and keyboard handlers only queue
+//! events (see [crate::events]); the [HANDLERS] run from the CPU loop. [new_idt] builds the
+//! IDT of each CPU.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
 use core::ptr::NonNull;
-use crate::serial;
+use core::time::Duration;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering};
 use lazy_static::lazy_static;
+use log::{debug, info, warn};
 use spin::Mutex;
 use x86_64::{PhysAddr, VirtAddr};
-use crate::HandlerTable;
+use crate::events::{self, Event};
+use crate::{io_apic, irq, pic, HandlerTable};
 use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
 use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
-use x86_64::registers::control::Cr2;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use x86_64::structures::paging::{FrameAllocator, Mapper, PhysFrame, Size4KiB};
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
 // This code is largely Copyright (c) 2019 Philipp Oppermann.
 // Gabriel Ferrer added:
 // - HANDLERS variable.
 // - Use of HANDLERS in init_idt, timer_interrupt_handler, keyboard_interrupt_handler
 
 lazy_static! {
+    /// The application's handlers. Only used outside interrupt handlers, see [crate::events].
     pub static ref HANDLERS: Mutex<Option<HandlerTable>> = Mutex::new(None);
 }
 
+/// The local APIC's registers: memory mapped at `address` in xAPIC mode, or MSRs once the
+/// LAPIC has been switched to x2APIC mode (see [has_x2apic]). Either way they are accessed by
+/// their [APICOffset].
+///
+/// The address and mode are atomics rather than the whole being behind a lock, so interrupt
+/// handlers can signal the end of interrupt whatever the interrupted code was doing with the
+/// LAPIC. Each CPU reaches its own LAPIC through the same registers.
 #[derive(Debug)]
 pub struct LAPICAddress {
-    address: *mut u32,
+    address: AtomicPtr<u32>,
+    x2apic: AtomicBool,
 }
-unsafe impl Send for LAPICAddress {}
-unsafe impl Sync for LAPICAddress {}
+
+/// The x2APIC registers are MSRs from 0x800 on, one per 16-byte xAPIC register
+const X2APIC_MSR_BASE: u32 = 0x800;
+const IA32_APIC_BASE: u32 = 0x1B;
 
 impl LAPICAddress {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
-            address: core::ptr::null_mut()
+            address: AtomicPtr::new(core::ptr::null_mut()),
+            x2apic: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the registers can be accessed: mapped, or in x2APIC mode.
+    pub fn is_present(&self) -> bool {
+        self.is_x2apic() || !self.address().is_null()
+    }
+
+    /// Whether the LAPIC is in x2APIC mode.
+    pub fn is_x2apic(&self) -> bool {
+        self.x2apic.load(Ordering::Relaxed)
+    }
+
+    /// The register window, null in x2APIC mode or before the LAPIC has been set up.
+    pub fn address(&self) -> *mut u32 {
+        self.address.load(Ordering::Relaxed)
+    }
+
+    fn set_address(&self, address: *mut u32) {
+        self.address.store(address, Ordering::Relaxed);
+    }
+
+    /// Reads a register. Returns None if the LAPIC is not set up, or for the registers x2APIC
+    /// mode does not have (APR, RRD and DFR; the CMCI LVT is left out too, as it is optional).
+    pub fn read(&self, offset: APICOffset) -> Option<u32> {
+        if !self.is_x2apic() {
+            let address = self.address();
+            return (!address.is_null()).then(|| unsafe { address.offset(offset as isize / 4).read_volatile() });
+        }
+        match offset {
+            APICOffset::Apr | APICOffset::Rrd | APICOffset::Dfr | APICOffset::LvtCmci => None,
+            // The ICR is a single 64-bit MSR in x2APIC mode
+            APICOffset::Icr2 => Some((unsafe { Msr::new(x2apic_msr(APICOffset::Icr1)).read() } >> 32) as u32),
+            _ => Some(unsafe { Msr::new(x2apic_msr(offset)).read() } as u32),
+        }
+    }
+
+    /// Writes a register. Does nothing if the LAPIC is not set up. Use [LAPICAddress::send_ipi]
+    /// for the ICR, which works differently in the two modes.
+    pub fn write(&self, offset: APICOffset, value: u32) {
+        let address = self.address();
+        if self.is_x2apic() {
+            unsafe { Msr::new(x2apic_msr(offset)).write(value as u64) };
+        } else if !address.is_null() {
+            unsafe { address.offset(offset as isize / 4).write_volatile(value) };
         }
     }
+
+    /// The ID of this LAPIC. x2APIC IDs are 32 bits, xAPIC IDs 8 bits.
+    pub fn id(&self) -> Option<u32> {
+        let id = self.read(APICOffset::Ir)?;
+        Some(if self.is_x2apic() { id } else { id >> 24 })
+    }
+
+    /// Sends the inter-processor interrupt `command` (the low half of the ICR) to the LAPIC with
+    /// ID `destination`, and waits until it has been delivered. Interrupts are disabled
+    /// meanwhile, so no interrupt handler can come between the two halves of the ICR.
+    pub fn send_ipi(&self, destination: u32, command: u32) {
+        without_interrupts(|| {
+            if self.is_x2apic() {
+                unsafe { Msr::new(x2apic_msr(APICOffset::Icr1)).write((destination as u64) << 32 | command as u64) };
+                return;
+            }
+            self.write(APICOffset::Icr2, destination << 24);
+            self.write(APICOffset::Icr1, command);
+            // Bit 12 is set while the IPI is pending
+            while self.read(APICOffset::Icr1).is_some_and(|icr| icr & (1 << 12) != 0) {
+                core::hint::spin_loop();
+            }
+        })
+    }
 }
 
-lazy_static! {
-    pub static ref LAPIC_ADDR: Mutex<LAPICAddress> = Mutex::new(LAPICAddress::new());
+impl Default for LAPICAddress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn x2apic_msr(offset: APICOffset) -> u32 {
+    X2APIC_MSR_BASE + offset as u32 / 0x10
+}
+
+/// Whether the CPU supports x2APIC mode (CPUID leaf 1, ECX bit 21).
+pub fn has_x2apic() -> bool {
+    unsafe { __cpuid(1) }.ecx & (1 << 21) != 0
+}
+
+/// Switches this CPU's LAPIC to x2APIC mode (bits 10 and 11 of IA32_APIC_BASE).
+fn enable_x2apic() {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    unsafe { apic_base.write(apic_base.read() | (1 << 11) | (1 << 10)) };
 }
 
+/// Number of timer interrupts handled so far.
+pub static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Time since the timer started, advanced by one timer period on every tick.
+pub static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Timer interrupt rate used unless [set_timer_frequency] asks for another one.
+pub const DEFAULT_TIMER_HZ: u32 = 60;
+static TIMER_HZ: AtomicU32 = AtomicU32::new(DEFAULT_TIMER_HZ);
+static TICK_NANOS: AtomicU64 = AtomicU64::new(1_000_000_000 / DEFAULT_TIMER_HZ as u64);
+/// How fast the LAPIC timer counts down (after the divide-by-16), measured at boot.
+static LAPIC_TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Input clock of the PIT, which the LAPIC timer is calibrated against.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// How long the calibration lets the LAPIC timer count.
+const CALIBRATION_MS: u64 = 10;
+
+pub static LAPIC_ADDR: LAPICAddress = LAPICAddress::new();
+
 // https://wiki.osdev.org/APIC
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Copy)]
@@ -111,6 +241,52 @@ pub enum APICOffset {
     R0x3F0 = 0x3F0,   // RESERVED = 0x3F0
 }
 
+impl APICOffset {
+    /// Registers that can be read back, in address order (reserved and write-only
+    /// registers are left out).
+    pub const READABLE: [APICOffset; 46] = [
+        APICOffset::Ir, APICOffset::Vr, APICOffset::Tpr, APICOffset::Apr, APICOffset::Ppr,
+        APICOffset::Rrd, APICOffset::Ldr, APICOffset::Dfr, APICOffset::Svr,
+        APICOffset::Isr1, APICOffset::Isr2, APICOffset::Isr3, APICOffset::Isr4,
+        APICOffset::Isr5, APICOffset::Isr6, APICOffset::Isr7, APICOffset::Isr8,
+        APICOffset::Tmr1, APICOffset::Tmr2, APICOffset::Tmr3, APICOffset::Tmr4,
+        APICOffset::Tmr5, APICOffset::Tmr6, APICOffset::Tmr7, APICOffset::Tmr8,
+        APICOffset::Irr1, APICOffset::Irr2, APICOffset::Irr3, APICOffset::Irr4,
+        APICOffset::Irr5, APICOffset::Irr6, APICOffset::Irr7, APICOffset::Irr8,
+        APICOffset::Esr, APICOffset::LvtCmci, APICOffset::Icr1, APICOffset::Icr2,
+        APICOffset::LvtT, APICOffset::LvtTsr, APICOffset::LvtPmcr, APICOffset::LvtLint0,
+        APICOffset::LvtLint1, APICOffset::LvtE, APICOffset::Ticr, APICOffset::Tccr,
+        APICOffset::Tdcr,
+    ];
+}
+
+/// Reads a local APIC register. Returns None before the LAPIC has been set up, see
+/// [LAPICAddress::read].
+pub fn read_lapic(offset: APICOffset) -> Option<u32> {
+    LAPIC_ADDR.read(offset)
+}
+
+/// Writes every present entry of the loaded IDT (vector, handler address, gate type and
+/// IST index) to `out`.
+pub fn dump_idt(out: &mut dyn fmt::Write) -> fmt::Result {
+    let idtr = x86_64::instructions::tables::sidt();
+    let entries = (idtr.limit as usize + 1) / 16;
+    let base = idtr.base.as_ptr::<[u64; 2]>();
+
+    for vector in 0..entries {
+        let [low, high] = unsafe { base.add(vector).read() };
+        let present = low & (1 << 47) != 0;
+        if !present {
+            continue;
+        }
+        let handler = (low & 0xFFFF) | ((low >> 32) & 0xFFFF_0000) | (high << 32);
+        let gate = if (low >> 40) & 0xF == 0xF { "trap" } else { "interrupt" };
+        let ist = (low >> 32) & 0x7;
+        writeln!(out, "{:#04x}: {:#018x} {:<9} ist={}", vector, handler, gate, ist)?;
+    }
+    Ok(())
+}
+
 pub struct AcpiHandlerImpl {
     physical_memory_offset: VirtAddr,
 }
@@ -160,78 +336,220 @@ impl AcpiHandler for AcpiHandlerImpl {
 }
 
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
+    static ref IDT: InterruptDescriptorTable = new_idt();
+}
 
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+/// Builds an IDT with every handler installed. The bootstrap processor loads [IDT], each
+/// application processor a copy of its own (see [crate::smp]).
+pub(crate) fn new_idt() -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
 
-        idt[InterruptIndex::Timer as u8].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt_handler);
+    crate::exceptions::install(&mut idt);
 
-        idt
-    };
+    idt[InterruptIndex::Timer as u8].set_handler_fn(timer_interrupt_handler);
+    idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt_handler);
+    idt[InterruptIndex::Wakeup as u8].set_handler_fn(wakeup_interrupt_handler);
+    crate::irq::install(&mut idt);
 
+    idt
 }
 
-unsafe fn init_io_apic(
-    ioapic_address: usize,
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
-    let virt_addr = map_apic(ioapic_address as u64, mapper, frame_allocator);
-
-    let ioapic_pointer = virt_addr.as_mut_ptr::<u32>();
-
-    unsafe {
-        ioapic_pointer.offset(0).write_volatile(0x12);
-        ioapic_pointer
-            .offset(4)
-            .write_volatile(InterruptIndex::Keyboard as u8 as u32);
+/// APIC ID of the CPU running this, from CPUID (so it works before the LAPIC is set up). The
+/// full x2APIC ID from leaf 0xB where there is one, the 8-bit ID from leaf 1 otherwise.
+pub fn local_apic_id() -> u32 {
+    if has_x2apic() && unsafe { __cpuid(0) }.eax >= 0xB {
+        return unsafe { __cpuid_count(0xB, 0) }.edx;
     }
+    unsafe { __cpuid(1) }.ebx >> 24
 }
 
+/// Sets up the LAPIC, in x2APIC mode if the CPU supports it and through the registers mapped
+/// from `local_apic_addr` otherwise.
 unsafe fn init_local_apic(
     local_apic_addr: usize,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) {
-    let virtual_address = map_apic(local_apic_addr as u64, mapper, frame_allocator);
+    if has_x2apic() {
+        enable_x2apic();
+        LAPIC_ADDR.x2apic.store(true, Ordering::Relaxed);
+        info!("LAPIC in x2APIC mode");
+    } else {
+        let virtual_address = map_apic(local_apic_addr as u64, mapper, frame_allocator);
+        LAPIC_ADDR.set_address(virtual_address.as_mut_ptr::<u32>());
+    }
+    init_timer();
+    init_keyboard();
+    debug!("init LAPIC_ADDR {:?}", LAPIC_ADDR);
+}
 
-    let lapic_pointer = virtual_address.as_mut_ptr::<u32>();
-    LAPIC_ADDR.lock().address = lapic_pointer;
-    unsafe {
-        init_timer(lapic_pointer);
-        init_keyboard(lapic_pointer);
+fn init_timer() {
+    enable_local_apic(&LAPIC_ADDR);
+
+    let lapic_timer_hz = calibrate_timer();
+    LAPIC_TIMER_HZ.store(lapic_timer_hz, Ordering::Relaxed);
+    info!("LAPIC timer counts at {} Hz", lapic_timer_hz);
+
+    LAPIC_ADDR.write(APICOffset::LvtT, InterruptIndex::Timer as u32 | (1 << 17)); // Periodic mode
+    set_timer_frequency(TIMER_HZ.load(Ordering::Relaxed));
+}
+
+/// Software-enables the LAPIC and sets the timer to count at the bus clock divided by 16.
+fn enable_local_apic(lapic: &LAPICAddress) {
+    let svr = lapic.read(APICOffset::Svr).unwrap_or(0);
+    lapic.write(APICOffset::Svr, svr | 0x100); // Set bit 8
+    lapic.write(APICOffset::Tdcr, 0x3); // Divide by 16 mode
+}
+
+/// Sets up the LAPIC of an application processor, in the mode of the bootstrap processor's,
+/// with its timer ticking at the current timer frequency. [set_timer_frequency] only changes
+/// the rate on the CPU that calls it.
+pub(crate) fn init_ap_local_apic() {
+    let lapic = &LAPIC_ADDR;
+    if lapic.is_x2apic() {
+        enable_x2apic();
     }
-    writeln!(serial(), "init LAPIC_ADDR {:?}", LAPIC_ADDR.lock()).unwrap();
+    enable_local_apic(lapic);
+    lapic.write(APICOffset::LvtT, InterruptIndex::Timer as u32 | (1 << 17)); // Periodic mode
+    lapic.write(APICOffset::Ticr, initial_count(TIMER_HZ.load(Ordering::Relaxed)));
 }
 
-unsafe fn init_timer(lapic_pointer: *mut u32) {
-    unsafe {
-        let svr = lapic_pointer.offset(APICOffset::Svr as isize / 4);
-        svr.write_volatile(svr.read_volatile() | 0x100); // Set bit 8
+/// Interrupts the CPU with APIC ID `apic_id`, waking it from `hlt`.
+pub(crate) fn send_wakeup(apic_id: u32) {
+    // Fixed delivery, level assert
+    LAPIC_ADDR.send_ipi(apic_id, InterruptIndex::Wakeup as u32 | (1 << 14));
+}
+
+/// Measures how many LAPIC timer counts pass in `CALIBRATION_MS`. Returns counts per second.
+fn calibrate_timer() -> u64 {
+    let lapic = &LAPIC_ADDR;
+    // Masked one-shot, so the countdown does not raise an interrupt
+    lapic.write(APICOffset::LvtT, InterruptIndex::Timer as u32 | (1 << 16));
+
+    let elapsed = pit_window(
+        || lapic.write(APICOffset::Ticr, u32::MAX),
+        || u32::MAX - lapic.read(APICOffset::Tccr).unwrap_or(u32::MAX),
+    );
+    lapic.write(APICOffset::Ticr, 0);
 
-        let lvt_lint1 = lapic_pointer.offset(APICOffset::LvtT as isize / 4);
-        lvt_lint1.write_volatile(0x20 | (1 << 17)); // Vector 0x20, periodic mode
+    elapsed as u64 * 1000 / CALIBRATION_MS
+}
 
-        let tdcr = lapic_pointer.offset(APICOffset::Tdcr as isize / 4);
-        tdcr.write_volatile(0x3); // Divide by 16 mode
+/// Busy-waits `CALIBRATION_MS`, timed by the PIT. `start` runs as the countdown starts, and
+/// what `stop` returns as it ends is returned. Also calibrates the TSC over the same window,
+/// see [crate::time].
+fn pit_window<T>(start: impl FnOnce(), stop: impl FnOnce() -> T) -> T {
+    let pit_count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    let (result, tsc_elapsed) = pit_countdown(
+        pit_count as u16,
+        || {
+            start();
+            crate::time::read_tsc()
+        },
+        |tsc_start| {
+            let result = stop();
+            (result, crate::time::read_tsc() - tsc_start)
+        },
+    );
+    crate::time::calibrate_tsc(tsc_elapsed * 1000 / CALIBRATION_MS);
+    result
+}
+
+/// Busy-waits for `duration`, timed by the PIT. Unlike [crate::time::sleep] this needs neither
+/// interrupts nor an invariant TSC, so it works during boot. Only one CPU may use the PIT's
+/// channel 2 at a time.
+pub(crate) fn pit_delay(duration: Duration) {
+    let mut remaining = (duration.as_nanos() * PIT_FREQUENCY as u128 / 1_000_000_000) as u64;
+    while remaining > 0 {
+        let count = remaining.min(u16::MAX as u64);
+        pit_countdown(count as u16, || (), |()| ());
+        remaining -= count;
+    }
+}
+
+/// Counts `pit_count` PIT periods down on channel 2 (the speaker channel, whose output can be
+/// polled on port 0x61) and busy-waits until it ends. What `start` returns as the countdown
+/// starts is passed to `stop` as it ends, and what `stop` returns is returned.
+fn pit_countdown<S, T>(pit_count: u16, start: impl FnOnce() -> S, stop: impl FnOnce(S) -> T) -> T {
+    let mut speaker_control = Port::<u8>::new(0x61);
+    let mut pit_command = Port::<u8>::new(0x43);
+    let mut pit_channel2 = Port::<u8>::new(0x42);
+
+    unsafe {
+        // Enable the channel 2 gate, keep the speaker itself off
+        let control = speaker_control.read();
+        speaker_control.write((control & !0x02) | 0x01);
+
+        // Channel 2, low then high byte, mode 0 (output goes high at terminal count)
+        pit_command.write(0b1011_0000);
+        pit_channel2.write(pit_count as u8);
+        pit_channel2.write((pit_count >> 8) as u8);
+        let started = start();
+
+        while speaker_control.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
 
-        let ticr = lapic_pointer.offset(APICOffset::Ticr as isize / 4);
-        ticr.write_volatile(0x0100_0000); // An arbitrary value for the initial value of the timer
+        let result = stop(started);
+        speaker_control.write(control);
+        result
     }
 }
 
-unsafe fn init_keyboard(lapic_pointer: *mut u32) {
+/// Starts PIT channel 0 as the timer tick (rate generator mode). The PIT cannot go slower
+/// than about 19 Hz.
+fn start_pit(hz: u32) {
+    let divisor = (PIT_FREQUENCY / hz as u64).clamp(1, u16::MAX as u64) as u16;
+    // The uptime advances by the period the divisor gives, which is not exactly 1/hz
+    TICK_NANOS.store(divisor as u64 * 1_000_000_000 / PIT_FREQUENCY, Ordering::Relaxed);
     unsafe {
-        let keyboard_register = lapic_pointer.offset(APICOffset::LvtLint1 as isize / 4);
-        keyboard_register.write_volatile(InterruptIndex::Keyboard as u8 as u32);
+        // Channel 0, low then high byte, mode 2
+        Port::<u8>::new(0x43).write(0b0011_0100);
+        let mut pit_channel0 = Port::<u8>::new(0x40);
+        pit_channel0.write(divisor as u8);
+        pit_channel0.write((divisor >> 8) as u8);
     }
+    info!("Timer ticking at {} Hz (PIT divisor {})", PIT_FREQUENCY / divisor as u64, divisor);
 }
 
-fn map_apic(
+/// Sets how many timer interrupts happen per second. Can be called before the LAPIC is set up,
+/// in which case it takes effect once the timer has been calibrated.
+pub fn set_timer_frequency(hz: u32) {
+    let hz = hz.max(1);
+    TIMER_HZ.store(hz, Ordering::Relaxed);
+    TICK_NANOS.store(1_000_000_000 / hz as u64, Ordering::Relaxed);
+
+    if pic::is_active() {
+        start_pit(hz);
+        return;
+    }
+
+    let lapic_timer_hz = LAPIC_TIMER_HZ.load(Ordering::Relaxed);
+    let lapic = &LAPIC_ADDR;
+    if !lapic.is_present() || lapic_timer_hz == 0 {
+        return;
+    }
+    let initial_count = initial_count(hz);
+    // Writing the initial count restarts the countdown
+    lapic.write(APICOffset::Ticr, initial_count);
+    info!("Timer ticking at {} Hz (initial count {})", hz, initial_count);
+}
+
+/// The LAPIC timer's initial count for `hz` interrupts per second.
+fn initial_count(hz: u32) -> u32 {
+    (LAPIC_TIMER_HZ.load(Ordering::Relaxed) / hz as u64).clamp(1, u32::MAX as u64) as u32
+}
+
+/// The timer interrupt rate in Hz.
+pub fn timer_frequency() -> u32 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
+
+fn init_keyboard() {
+    LAPIC_ADDR.write(APICOffset::LvtLint1, InterruptIndex::Keyboard as u8 as u32);
+}
+
+pub(crate) fn map_apic(
     physical_address: u64,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
@@ -255,91 +573,128 @@ fn map_apic(
     page.start_address()
 }
 
-pub fn init_apic(rsdp: usize, offset: u64, mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> *mut u32 {
+/// Sets up the interrupt controllers: the local and IO APICs described by the ACPI tables at
+/// `rsdp`, or the 8259 PICs and the PIT if there is no RSDP or the tables list no APIC (see
+/// [crate::pic]). Returns the LAPIC's registers, or null when the PICs are used.
+pub fn init_interrupt_controller(rsdp: Option<usize>, offset: u64, mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> *mut u32 {
     let handler = AcpiHandlerImpl::new(VirtAddr::new(offset));
-    let acpi_tables = unsafe { AcpiTables::from_rsdp(handler, rsdp).expect("Failed to parse ACPI tables") };
-    let platform_info = acpi_tables.platform_info().expect("Failed to get platform info");
+    let Some(rsdp) = rsdp else {
+        return init_pic(format_args!("no RSDP"));
+    };
+    let acpi_tables = match unsafe { AcpiTables::from_rsdp(handler, rsdp) } {
+        Ok(acpi_tables) => acpi_tables,
+        Err(error) => return init_pic(format_args!("failed to parse the ACPI tables: {:?}", error)),
+    };
+    let platform_info = match acpi_tables.platform_info() {
+        Ok(platform_info) => platform_info,
+        Err(error) => return init_pic(format_args!("failed to get the platform info: {:?}", error)),
+    };
 
     match platform_info.interrupt_model {
         acpi::InterruptModel::Apic(apic) => {
-            let io_apic_address = apic.io_apics[0].address;
-            unsafe { init_io_apic(io_apic_address as usize, mapper, frame_allocator); }
+            pic::disable();
+            io_apic::init(&apic.io_apics, &apic.interrupt_source_overrides, mapper, frame_allocator);
+
+            let keyboard_gsi = io_apic::isa_irq_to_gsi(1);
+            if !io_apic::route(keyboard_gsi, InterruptIndex::Keyboard as u8, local_apic_id()) {
+                warn!("No IO APIC handles the keyboard interrupt (GSI {})", keyboard_gsi);
+            }
+            // COM1 raises ISA IRQ 4
+            irq::register_isa_irq(4, crate::receive_serial).expect("Failed to route the COM1 interrupt");
 
             let local_apic_address = apic.local_apic_address;
             unsafe { init_local_apic(local_apic_address as usize, mapper, frame_allocator); }
+
+            if let Some(processor_info) = &platform_info.processor_info {
+                crate::smp::set_application_processors(&processor_info.application_processors);
+            }
         },
-        _ => {
-            // handler other interrupt models, if necessary
-        }
+        _ => return init_pic(format_args!("the ACPI tables list no APIC")),
     }
 
-    disable_pic();
-
-    writeln!(serial(), "APIC setup completed, pending interrupt and setup IDT.").unwrap();
-    writeln!(serial(), "LAPIC address: {:?}", LAPIC_ADDR.lock()).unwrap();
-    LAPIC_ADDR.lock().address
+    info!("APIC setup completed, pending interrupt and setup IDT.");
+    debug!("LAPIC address: {:?}", LAPIC_ADDR);
+    // Null in x2APIC mode, which has no register window
+    LAPIC_ADDR.address()
 }
 
-fn disable_pic() {
-    // Disable any unneeded PIC features, such as timer or keyboard to prevent it from firing interrupts
-
-    unsafe {
-        Port::<u8>::new(0x21).write(0xFF);
-        Port::<u8>::new(0xA1).write(0xFF);
-    }
+/// Sets up the PICs, with the PIT as the timer, and returns a null LAPIC pointer. The timer and
+/// keyboard are ordinary ISA IRQs here, registered like any other device.
+fn init_pic(reason: fmt::Arguments) -> *mut u32 {
+    warn!("Falling back to the 8259 PIC: {}", reason);
+    pic::init();
+    // Calibrates the TSC, there is no LAPIC timer to calibrate
+    pit_window(|| (), || ());
+    set_timer_frequency(TIMER_HZ.load(Ordering::Relaxed));
+
+    irq::register_isa_irq(0, tick).expect("Failed to route the PIT interrupt");
+    irq::register_isa_irq(1, read_keyboard).expect("Failed to route the keyboard interrupt");
+    // COM1 raises ISA IRQ 4
+    irq::register_isa_irq(4, crate::receive_serial).expect("Failed to route the COM1 interrupt");
+    info!("PIC setup completed, pending interrupt and setup IDT.");
+    core::ptr::null_mut()
 }
 
-fn end_interrupt() {
-    let binding = LAPIC_ADDR.lock();
-    unsafe { binding.address.offset(APICOffset::Eoi as isize / 4).write_volatile(0); }
+pub(crate) fn end_interrupt() {
+    if pic::is_active() {
+        return pic::end_interrupt();
+    }
+    LAPIC_ADDR.write(APICOffset::Eoi, 0);
 }
 
-/// Initializes the interrupt table with the given interrupt handlers.
+/// Initializes the interrupt table with the given interrupt handlers. `lapic_pointer` is the
+/// LAPIC's register window, null in x2APIC mode or without an APIC.
 pub fn init_idt(handlers: HandlerTable, lapic_pointer: *mut u32) {
-    LAPIC_ADDR.lock().address = lapic_pointer;
-    writeln!(serial(), "initialize IDT with LAPIC_ADDR {:?}", LAPIC_ADDR.lock()).unwrap();
+    LAPIC_ADDR.set_address(lapic_pointer);
+    debug!("initialize IDT with LAPIC_ADDR {:?}", LAPIC_ADDR);
     *(HANDLERS.lock()) = Some(handlers);
 
     IDT.load();
     x86_64::instructions::interrupts::enable();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame)
-{
-    writeln!(serial(), "EXCEPTION: BREAKPOINT\n{:#?}", stack_frame).unwrap();
-}
-
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    panic!("EXCEPTION: PAGE FAULT access address: {:?}\n ErrorCode: {:?}\n{:#?}", Cr2::read(), error_code, stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> !
-{
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
 const PIC_1_OFFSET: u8 = 0x20;
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// Sent by [crate::smp::run_on] to wake a CPU for its queued work
+    Wakeup = 0xF0,
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    
-    let h = &*HANDLERS.lock();
-    if let Some(handler) = h {
-        handler.handle_timer();
+    // The uptime and the timer events belong to the bootstrap processor
+    match crate::smp::current() {
+        Some(cpu) if cpu.index() != 0 => cpu.tick(),
+        _ => tick(),
     }
+    end_interrupt();
+}
 
+extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // Nothing to do, the CPU loop runs the queued work once the CPU is out of `hlt`
     end_interrupt();
 }
 
+/// Counts a timer tick, from the LAPIC timer or the PIT.
+fn tick() {
+    if let Some(cpu) = crate::smp::current() {
+        cpu.tick();
+    }
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NANOS.fetch_add(TICK_NANOS.load(Ordering::Relaxed), Ordering::Relaxed);
+
+    events::push(Event::Tick);
+}
+
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    read_keyboard();
+    end_interrupt();
+}
 
+/// Reads a scancode from the PS/2 controller and queues the key, if it completes one.
+fn read_keyboard() {
     lazy_static! {
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
             Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key,
@@ -353,13 +708,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let scancode: u8 = unsafe { port.read() };
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
-            let h = &*HANDLERS.lock();
-            if let Some(handler) = h {
-                handler.handle_keyboard(key);
-            }
+            events::push(Event::Key(key));
         }
     }
-
-    end_interrupt();
-
-}
\ No newline at end of file
+}
@@ -0,0 +1,285 @@
+//! Driver for the IO APICs, which turn device interrupt lines into interrupts for the local
+//! APICs.
+//!
+//! Every IO APIC listed in the MADT handles a range of global system interrupts (GSIs) starting
+//! at its GSI base, with one redirection entry per line. The entry holds the vector, the
+//! destination APIC, how the line signals (polarity and trigger mode) and a mask bit.
+//!
+//! ISA IRQs are the GSIs of the same number, active high and edge triggered, unless the MADT
+//! has an interrupt source override for them. QEMU, for example, wires the PIT on ISA IRQ 0 to
+//! GSI 2. Use [isa_irq_to_gsi] for ISA devices; [route] picks up the override's polarity and
+//! trigger mode by itself.
+
+use alloc::vec::Vec;
+use core::fmt;
+use acpi::platform::interrupt::{self as madt, InterruptSourceOverride};
+use log::{debug, info};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+
+/// Register offsets in the IO APIC's indirect register window
+const ID_REGISTER: u32 = 0x00;
+const VERSION_REGISTER: u32 = 0x01;
+/// Redirection entry n is registers 0x10 + 2n (low half) and 0x11 + 2n (high half)
+const REDIRECTION_TABLE: u32 = 0x10;
+
+/// Number of ISA IRQs, which are identity mapped to GSIs unless overridden
+const ISA_IRQS: u32 = 16;
+
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+static OVERRIDES: Mutex<Vec<SourceOverride>> = Mutex::new(Vec::new());
+
+/// Which level of an interrupt line is active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Whether an interrupt is raised by a change of the line or for as long as it is active.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// The settings of one interrupt line. Delivery is always fixed, to one APIC by its ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RedirectionEntry {
+    pub vector: u8,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+    pub masked: bool,
+    /// APIC ID of the CPU that handles the interrupt
+    pub destination: u8,
+}
+
+impl RedirectionEntry {
+    fn from_bits(bits: u64) -> Self {
+        Self {
+            vector: bits as u8,
+            polarity: if bits & (1 << 13) != 0 { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+            trigger_mode: if bits & (1 << 15) != 0 { TriggerMode::Level } else { TriggerMode::Edge },
+            masked: bits & (1 << 16) != 0,
+            destination: (bits >> 56) as u8,
+        }
+    }
+
+    fn bits(&self) -> u64 {
+        let mut bits = self.vector as u64 | (self.destination as u64) << 56;
+        if self.polarity == Polarity::ActiveLow {
+            bits |= 1 << 13;
+        }
+        if self.trigger_mode == TriggerMode::Level {
+            bits |= 1 << 15;
+        }
+        if self.masked {
+            bits |= 1 << 16;
+        }
+        bits
+    }
+}
+
+/// An ISA IRQ that the MADT says is wired differently.
+#[derive(Debug, Clone, Copy)]
+struct SourceOverride {
+    isa_irq: u8,
+    gsi: u32,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+}
+
+impl From<&InterruptSourceOverride> for SourceOverride {
+    fn from(source: &InterruptSourceOverride) -> Self {
+        // "Same as bus" means the ISA defaults
+        Self {
+            isa_irq: source.isa_source,
+            gsi: source.global_system_interrupt,
+            polarity: match source.polarity {
+                madt::Polarity::ActiveLow => Polarity::ActiveLow,
+                _ => Polarity::ActiveHigh,
+            },
+            trigger_mode: match source.trigger_mode {
+                madt::TriggerMode::Level => TriggerMode::Level,
+                _ => TriggerMode::Edge,
+            },
+        }
+    }
+}
+
+/// One IO APIC and the range of GSIs it handles.
+struct IoApic {
+    id: u8,
+    version: u8,
+    /// The register window: an index register, and the data register 16 bytes further
+    registers: *mut u32,
+    gsi_base: u32,
+    entries: u32,
+}
+
+// The register window is only touched with the IO_APICS lock held
+unsafe impl Send for IoApic {}
+
+impl IoApic {
+    /// Reads the version register of the IO APIC mapped at `registers`.
+    unsafe fn new(id: u8, registers: *mut u32, gsi_base: u32) -> Self {
+        let mut io_apic = Self { id, version: 0, registers, gsi_base, entries: 0 };
+        let version = io_apic.read(VERSION_REGISTER);
+        io_apic.version = version as u8;
+        // Bits 16..24 hold the index of the last redirection entry
+        io_apic.entries = ((version >> 16) & 0xFF) + 1;
+        io_apic
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            self.registers.write_volatile(register);
+            self.registers.offset(4).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            self.registers.write_volatile(register);
+            self.registers.offset(4).write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+
+    fn read_entry(&self, index: u32) -> RedirectionEntry {
+        let low = self.read(REDIRECTION_TABLE + 2 * index) as u64;
+        let high = self.read(REDIRECTION_TABLE + 2 * index + 1) as u64;
+        RedirectionEntry::from_bits(high << 32 | low)
+    }
+
+    fn write_entry(&self, index: u32, entry: RedirectionEntry) {
+        let bits = entry.bits();
+        // Mask the line while the entry is half written
+        self.write(REDIRECTION_TABLE + 2 * index, (bits as u32) | 1 << 16);
+        self.write(REDIRECTION_TABLE + 2 * index + 1, (bits >> 32) as u32);
+        self.write(REDIRECTION_TABLE + 2 * index, bits as u32);
+    }
+}
+
+/// Maps every IO APIC in `io_apics`, masks all their lines and remembers the ISA overrides.
+pub(crate) fn init(
+    io_apics: &[madt::IoApic],
+    overrides: &[InterruptSourceOverride],
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let mut apics = IO_APICS.lock();
+    for entry in io_apics {
+        let virt_addr = crate::interrupts::map_apic(entry.address as u64, mapper, frame_allocator);
+        let io_apic = unsafe { IoApic::new(entry.id, virt_addr.as_mut_ptr(), entry.global_system_interrupt_base) };
+        for index in 0..io_apic.entries {
+            let mut redirection = io_apic.read_entry(index);
+            redirection.masked = true;
+            io_apic.write_entry(index, redirection);
+        }
+        info!("IO APIC {} (version {:#x}, hardware ID {}): GSIs {}-{}", io_apic.id, io_apic.version,
+              io_apic.read(ID_REGISTER) >> 24 & 0xF, io_apic.gsi_base, io_apic.gsi_base + io_apic.entries - 1);
+        apics.push(io_apic);
+    }
+
+    let mut source_overrides = OVERRIDES.lock();
+    for source in overrides {
+        let source = SourceOverride::from(source);
+        debug!("ISA IRQ {} is GSI {} ({:?}, {:?})", source.isa_irq, source.gsi, source.polarity, source.trigger_mode);
+        source_overrides.push(source);
+    }
+}
+
+/// The GSI that ISA IRQ `irq` is wired to.
+pub fn isa_irq_to_gsi(irq: u8) -> u32 {
+    OVERRIDES.lock().iter().find(|source| source.isa_irq == irq).map_or(irq as u32, |source| source.gsi)
+}
+
+/// How `gsi` signals: as its ISA override says, as an ISA line for the first 16 GSIs, and
+/// otherwise like a PCI line (active low, level triggered).
+fn line_signal(gsi: u32) -> (Polarity, TriggerMode) {
+    match OVERRIDES.lock().iter().find(|source| source.gsi == gsi) {
+        Some(source) => (source.polarity, source.trigger_mode),
+        None if gsi < ISA_IRQS => (Polarity::ActiveHigh, TriggerMode::Edge),
+        None => (Polarity::ActiveLow, TriggerMode::Level),
+    }
+}
+
+/// Runs `f` on the IO APIC handling `gsi` and the index of its entry. Returns None if no IO
+/// APIC handles `gsi`.
+fn with_line<T>(gsi: u32, f: impl FnOnce(&IoApic, u32) -> T) -> Option<T> {
+    without_interrupts(|| {
+        let apics = IO_APICS.lock();
+        let io_apic = apics.iter().find(|io_apic| io_apic.handles(gsi))?;
+        Some(f(io_apic, gsi - io_apic.gsi_base))
+    })
+}
+
+/// The redirection entry of `gsi`, or None if no IO APIC handles it.
+pub fn entry(gsi: u32) -> Option<RedirectionEntry> {
+    with_line(gsi, |io_apic, index| io_apic.read_entry(index))
+}
+
+/// Delivers `gsi` as `vector` to the CPU with APIC ID `destination` and unmasks it, with the
+/// polarity and trigger mode the MADT gives for the line. Returns false if no IO APIC handles
+/// `gsi`, or `destination` does not fit in the 8 bits of a redirection entry.
+pub fn route(gsi: u32, vector: u8, destination: u32) -> bool {
+    let Ok(destination) = u8::try_from(destination) else {
+        return false;
+    };
+    let (polarity, trigger_mode) = line_signal(gsi);
+    let entry = RedirectionEntry { vector, polarity, trigger_mode, masked: false, destination };
+    with_line(gsi, |io_apic, index| io_apic.write_entry(index, entry)).is_some()
+}
+
+/// Stops `gsi` from raising interrupts. Returns false if no IO APIC handles it.
+pub fn mask(gsi: u32) -> bool {
+    update(gsi, |entry| entry.masked = true)
+}
+
+/// Lets `gsi` raise interrupts again. Returns false if no IO APIC handles it.
+pub fn unmask(gsi: u32) -> bool {
+    update(gsi, |entry| entry.masked = false)
+}
+
+/// Sends `gsi` to the CPU with APIC ID `destination` from now on. Returns false if no IO APIC
+/// handles it, or `destination` does not fit in 8 bits.
+pub fn set_destination(gsi: u32, destination: u32) -> bool {
+    let Ok(destination) = u8::try_from(destination) else {
+        return false;
+    };
+    update(gsi, |entry| entry.destination = destination)
+}
+
+fn update(gsi: u32, change: impl FnOnce(&mut RedirectionEntry)) -> bool {
+    with_line(gsi, |io_apic, index| {
+        let mut entry = io_apic.read_entry(index);
+        change(&mut entry);
+        io_apic.write_entry(index, entry);
+    }).is_some()
+}
+
+/// Writes every IO APIC and its unmasked redirection entries to `out`.
+pub fn dump(out: &mut dyn fmt::Write) -> fmt::Result {
+    let apics = without_interrupts(|| {
+        IO_APICS.lock().iter().map(|io_apic| {
+            let entries: Vec<_> = (0..io_apic.entries).map(|index| io_apic.read_entry(index)).collect();
+            (io_apic.id, io_apic.version, io_apic.gsi_base, entries)
+        }).collect::<Vec<_>>()
+    });
+    if apics.is_empty() {
+        return writeln!(out, "No IO APIC set up");
+    }
+    for (id, version, gsi_base, entries) in apics {
+        writeln!(out, "IO APIC {} (version {:#x}), GSIs {}-{}:", id, version, gsi_base, gsi_base + entries.len() as u32 - 1)?;
+        for (index, entry) in entries.iter().enumerate().filter(|(_, entry)| !entry.masked) {
+            writeln!(out, "  GSI {:>3} -> vector {:#04x} on APIC {} ({:?}, {:?})", gsi_base + index as u32,
+                     entry.vector, entry.destination, entry.polarity, entry.trigger_mode)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! Registration of device interrupts (IRQs) at run time.
+//!
+//! The IDT is built once, with a small trampoline on each vector from [FIRST_VECTOR] on. A
+//! trampoline looks up the handler registered for its vector, calls it and signals the end of
+//! the interrupt to the local APIC. [register_irq] picks a free vector, stores the handler
+//! and points the IO APIC entry of the interrupt line at the vector (see [crate::io_apic]).
+//! Without APICs the vectors are those of the PIC lines instead (see [crate::pic]):
+//!
+//! ```ignore
+//! // COM2 raises ISA IRQ 3
+//! kernel::irq::register_isa_irq(3, || { /* read the UART */ }).expect("no free vector");
+//! ```
+//!
+//! Handlers run in interrupt context with interrupts disabled, so they should do as little as
+//! possible, e.g. read the device and queue what they got (see [crate::events]).
+
+use alloc::boxed::Box;
+use core::fmt;
+use spin::Mutex;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use crate::{interrupts, io_apic, pic, smp};
+
+/// First vector handed out by [register_irq]; the vectors below are the CPU exceptions and
+/// the fixed timer and keyboard interrupts.
+pub const FIRST_VECTOR: u8 = 0x30;
+/// Number of vectors available to [register_irq].
+pub const VECTOR_COUNT: usize = 32;
+
+type IrqHandler = Box<dyn FnMut() + Send>;
+
+static HANDLERS: [Mutex<Option<IrqHandler>>; VECTOR_COUNT] = [const { Mutex::new(None) }; VECTOR_COUNT];
+
+/// Why [register_irq] failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// Every vector from [FIRST_VECTOR] on is taken.
+    NoFreeVector,
+    /// No IO APIC handles this global system interrupt (or none has been set up yet).
+    UnknownGsi(u32),
+    /// The PIC line has a handler already; PIC lines cannot be moved to another vector.
+    LineTaken(u32),
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IrqError::NoFreeVector => write!(f, "no free interrupt vector"),
+            IrqError::UnknownGsi(gsi) => write!(f, "no IO APIC handles GSI {}", gsi),
+            IrqError::LineTaken(gsi) => write!(f, "PIC line {} already has a handler", gsi),
+        }
+    }
+}
+
+/// Calls `handler` whenever global system interrupt `gsi` is raised. Returns the vector it
+/// was given. The interrupt is delivered to the bootstrap processor, whichever CPU registers
+/// it, since handlers queue events that only the bootstrap processor's CPU loop takes out.
+pub fn register_irq(gsi: u32, handler: impl FnMut() + Send + 'static) -> Result<u8, IrqError> {
+    // Before smp::init only the bootstrap processor runs
+    let bsp_apic_id = smp::cpu(0).map_or_else(interrupts::local_apic_id, smp::PerCpu::apic_id);
+    let vector = x86_64::instructions::interrupts::without_interrupts(|| {
+        if pic::is_active() {
+            return register_pic_line(gsi, Box::new(handler));
+        }
+        let slot = HANDLERS.iter().position(|slot| slot.lock().is_none()).ok_or(IrqError::NoFreeVector)?;
+        let vector = FIRST_VECTOR + slot as u8;
+        *HANDLERS[slot].lock() = Some(Box::new(handler));
+
+        if !io_apic::route(gsi, vector, bsp_apic_id) {
+            *HANDLERS[slot].lock() = None;
+            return Err(IrqError::UnknownGsi(gsi));
+        }
+        Ok(vector)
+    })?;
+    log::debug!("GSI {} routed to vector {:#x}", gsi, vector);
+    Ok(vector)
+}
+
+/// With the PICs the GSIs are the PIC lines, and line n always raises vector [FIRST_VECTOR] + n.
+fn register_pic_line(gsi: u32, handler: IrqHandler) -> Result<u8, IrqError> {
+    let line = u8::try_from(gsi).ok().filter(|line| *line < pic::LINES).ok_or(IrqError::UnknownGsi(gsi))?;
+    let mut slot = HANDLERS[line as usize].lock();
+    if slot.is_some() {
+        return Err(IrqError::LineTaken(gsi));
+    }
+    *slot = Some(handler);
+    pic::unmask(line);
+    Ok(FIRST_VECTOR + line)
+}
+
+/// Like [register_irq], for the GSI that ISA IRQ `irq` is wired to.
+pub fn register_isa_irq(irq: u8, handler: impl FnMut() + Send + 'static) -> Result<u8, IrqError> {
+    register_irq(io_apic::isa_irq_to_gsi(irq), handler)
+}
+
+fn dispatch(vector: u8) {
+    if let Some(handler) = HANDLERS[(vector - FIRST_VECTOR) as usize].lock().as_mut() {
+        handler();
+    }
+    interrupts::end_interrupt();
+}
+
+macro_rules! trampolines {
+    ($($index:literal)*) => {
+        [$({
+            extern "x86-interrupt" fn trampoline(_stack_frame: InterruptStackFrame) {
+                dispatch(FIRST_VECTOR + $index);
+            }
+            trampoline as extern "x86-interrupt" fn(InterruptStackFrame)
+        }),*]
+    };
+}
+
+static TRAMPOLINES: [extern "x86-interrupt" fn(InterruptStackFrame); VECTOR_COUNT] = trampolines!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+);
+
+/// Installs the trampolines in `idt`.
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+    for (index, trampoline) in TRAMPOLINES.iter().enumerate() {
+        idt[FIRST_VECTOR + index as u8].set_handler_fn(*trampoline);
+    }
+}
@@ -1,21 +1,96 @@
 // Original code from rust-osdev/bootloader crate https://github.com/rust-osdev/bootloader
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
 
 use core::cell::UnsafeCell;
+use core::fmt;
 use core::panic::PanicInfo;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
 use uart_16550::SerialPort;
 use pc_keyboard::DecodedKey;
+use events::Event;
+use ring_buffer::RingBuffer;
 
-mod interrupts;
+pub mod backtrace;
+pub mod events;
+pub mod exceptions;
+pub mod gdt;
+pub mod interrupts;
+pub mod io_apic;
+pub mod irq;
+pub mod logging;
+pub mod pic;
+pub mod pixel;
+pub mod ring_buffer;
+pub mod smp;
+pub mod time;
+pub mod timers;
 
 extern crate alloc;
 
-pub fn serial() -> SerialPort {
-    let mut port = unsafe { SerialPort::new(0x3F8) };
-    port.init();
-    port
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+lazy_static! {
+    /// COM1, initialized once on first use.
+    static ref SERIAL: Mutex<SerialPort> = {
+        let mut port = unsafe { SerialPort::new(0x3F8) };
+        port.init();
+        Mutex::new(port)
+    };
+}
+
+/// Writes to COM1. A `write!` holds the port's lock with interrupts disabled from start to end,
+/// so output from interrupt handlers, other CPUs and normal code never interleaves within it.
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        x86_64::instructions::interrupts::without_interrupts(|| SERIAL.lock().write_str(s))
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments) -> fmt::Result {
+        x86_64::instructions::interrupts::without_interrupts(|| SERIAL.lock().write_fmt(args))
+    }
+}
+
+/// Bytes received on COM1 while no serial handler was installed.
+static SERIAL_INPUT: RingBuffer<u8, 256> = RingBuffer::new();
+
+/// Queues every byte waiting in the UART as an event. The COM1 interrupt handler.
+pub(crate) fn receive_serial() {
+    let mut port = SERIAL.lock();
+    while let Ok(byte) = port.try_receive() {
+        events::push(Event::Serial(byte));
+    }
+}
+
+/// Returns the next byte received on COM1, if any. Only useful when no serial handler is
+/// installed in the [HandlerTable], since the handler consumes every byte as it arrives.
+/// Bytes only get here once the CPU loop has handled their events.
+pub fn serial_read() -> Option<u8> {
+    SERIAL_INPUT.pop()
+}
+
+/// Returns a writer for the serial port, for use with `write!`/`writeln!`.
+/// Prefer the `log` macros (see [logging]) for diagnostic messages.
+pub fn serial() -> SerialWriter {
+    SerialWriter
+}
+
+/// Number of timer interrupts since the IDT was loaded.
+pub fn ticks() -> u64 {
+    interrupts::TICKS.load(Ordering::Relaxed)
+}
+
+/// Time elapsed since the IDT was loaded, counted in timer periods, so it only advances once
+/// per tick (see [HandlerTable::timer_frequency]). Never goes backwards.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(interrupts::UPTIME_NANOS.load(Ordering::Relaxed))
 }
 
 /// Table of interrupt handlers. This struct uses the
@@ -24,23 +99,49 @@ pub fn serial() -> SerialPort {
 /// up the handlers. When ready, call the **.start()** method to start up your pluggable
 /// interrupt operating system.
 ///
-/// For now, it only includes timer and keyboard handlers.
+/// For now, it only includes timer, keyboard and serial input handlers, plus periodic
+/// handlers built on the software [timers].
+///
+/// Handlers can be plain functions or closures owning their state, so an application does not
+/// need `static mut` globals; state used by several handlers can be shared through an
+/// `Arc<Mutex<_>>`:
+///
+/// ```ignore
+/// let game = Arc::new(Mutex::new(PongGame::new(width, height)));
+/// let keys = game.clone();
+/// HandlerTable::new()
+///     .keyboard(move |key| keys.lock().handle_key(key))
+///     .timer(move || game.lock().update())
+///     .start(lapic_ptr)
+/// ```
 pub struct HandlerTable {
-    timer: Option<fn()>,
-    keyboard: Option<fn(DecodedKey)>,
-    startup: Option<fn()>,
+    timer: Option<Box<dyn FnMut() + Send>>,
+    keyboard: Option<Box<dyn FnMut(DecodedKey) + Send>>,
+    serial: Option<Box<dyn FnMut(u8) + Send>>,
+    timer_frequency: Option<u32>,
+    periodic: Vec<(Duration, Box<dyn FnMut() + Send>)>,
+    startup: Option<Box<dyn FnOnce() + Send>>,
     cpu_loop: fn() -> !,
 }
 
 impl HandlerTable {
     /// Creates a new HandlerTable with no handlers.
     pub fn new() -> Self {
-        HandlerTable {timer: None, keyboard: None, startup: None, cpu_loop: hlt_loop}
+        HandlerTable {timer: None, keyboard: None, serial: None, timer_frequency: None, periodic: Vec::new(),
+                      startup: None, cpu_loop: event_loop}
     }
 
     /// Starts up a simple operating system using the specified handlers.
-    pub fn start(self, lapic_ptr: *mut u32) -> ! {
-        self.startup.map(|f| f());
+    pub fn start(mut self, lapic_ptr: *mut u32) -> ! {
+        if let Some(hz) = self.timer_frequency {
+            interrupts::set_timer_frequency(hz);
+        }
+        for (period, handler) in self.periodic.drain(..) {
+            timers::every(period, handler);
+        }
+        if let Some(startup) = self.startup.take() {
+            startup();
+        }
         let fore = self.cpu_loop;
         
         interrupts::init_idt(self, lapic_ptr);
@@ -50,14 +151,45 @@ impl HandlerTable {
 
     /// Sets the timer handler.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
-    pub fn timer(mut self, timer_handler: fn()) -> Self {
-        self.timer = Some(timer_handler);
+    pub fn timer(mut self, timer_handler: impl FnMut() + Send + 'static) -> Self {
+        self.timer = Some(Box::new(timer_handler));
         self
     }
 
-    /// Called by the low-level interrupt routines to handle a timer event.
-    pub fn handle_timer(&self) {
-        if let Some(timer) = self.timer {
+    /// Sets how many times per second the timer handler is called. Without it the timer runs
+    /// at [interrupts::DEFAULT_TIMER_HZ].
+    ///
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn timer_frequency(mut self, hz: u32) -> Self {
+        self.timer_frequency = Some(hz);
+        self
+    }
+
+    /// Adds a handler called every `period`, independently of the timer handler and of other
+    /// periodic handlers. Can be used several times. See [timers] for timers that can be
+    /// added and cancelled at any time.
+    ///
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn periodic(mut self, period: Duration, handler: impl FnMut() + Send + 'static) -> Self {
+        self.periodic.push((period, Box::new(handler)));
+        self
+    }
+
+    /// Runs the handlers for `event`. Timer events also run the expired software [timers].
+    pub fn dispatch(&mut self, event: Event) {
+        match event {
+            Event::Tick => {
+                self.handle_timer();
+                timers::run_expired();
+            }
+            Event::Key(key) => self.handle_keyboard(key),
+            Event::Serial(byte) => self.handle_serial(byte),
+        }
+    }
+
+    /// Called by [HandlerTable::dispatch] to handle a timer event.
+    pub fn handle_timer(&mut self) {
+        if let Some(timer) = &mut self.timer {
             (timer)()
         }
     }
@@ -66,27 +198,49 @@ impl HandlerTable {
     /// enum comes from the [pc_keyboard](https://crates.io/crates/pc-keyboard) crate.
     ///
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
-    pub fn keyboard(mut self, keyboard_handler: fn(DecodedKey)) -> Self {
-        self.keyboard = Some(keyboard_handler);
+    pub fn keyboard(mut self, keyboard_handler: impl FnMut(DecodedKey) + Send + 'static) -> Self {
+        self.keyboard = Some(Box::new(keyboard_handler));
         self
     }
 
-    /// Called by the low-level interrupt routines to handle a keyboard event.
-    pub fn handle_keyboard(&self, key: DecodedKey) {
-        if let Some(keyboard) = self.keyboard {
+    /// Called by [HandlerTable::dispatch] to handle a keyboard event.
+    pub fn handle_keyboard(&mut self, key: DecodedKey) {
+        if let Some(keyboard) = &mut self.keyboard {
             (keyboard)(key)
         }
     }
 
-    /// Sets the startup handler.
+    /// Sets the serial input handler, called with every byte received on COM1 (e.g. typed into
+    /// the host terminal when QEMU runs with `-serial stdio`).
+    ///
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn serial(mut self, serial_handler: impl FnMut(u8) + Send + 'static) -> Self {
+        self.serial = Some(Box::new(serial_handler));
+        self
+    }
+
+    /// Called by [HandlerTable::dispatch] to hand over a received serial byte.
+    /// Without a serial handler the bytes are queued for [serial_read].
+    pub fn handle_serial(&mut self, byte: u8) {
+        match &mut self.serial {
+            Some(serial) => (serial)(byte),
+            None => {
+                // Drop input when nobody reads it.
+                let _ = SERIAL_INPUT.push(byte);
+            }
+        }
+    }
+
+    /// Sets the startup handler, called once just before interrupts are enabled.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
-    pub fn startup(mut self, startup_handler: fn()) -> Self {
-        self.startup = Some(startup_handler);
+    pub fn startup(mut self, startup_handler: impl FnOnce() + Send + 'static) -> Self {
+        self.startup = Some(Box::new(startup_handler));
         self
     }
 
-    /// Sets the cpu loop handler.
-    /// This function should contain an infinite loop.
+    /// Sets the cpu loop handler, replacing [event_loop].
+    /// This function should contain an infinite loop, which calls [run_pending_events] for the
+    /// other handlers to run.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
     pub fn cpu_loop(mut self, cpu_loop: fn() -> !) -> Self {
         self.cpu_loop = cpu_loop;
@@ -94,15 +248,105 @@ impl HandlerTable {
     }
 }
 
+/// Runs the handlers for every event the interrupt handlers have queued so far, then shows
+/// the records logged for the screen meanwhile (see [logging::flush_screen]).
+pub fn run_pending_events() {
+    {
+        // Interrupt handlers never take this lock, so it is held with interrupts enabled.
+        let mut handlers = interrupts::HANDLERS.lock();
+        if let Some(handlers) = handlers.as_mut() {
+            while let Some(event) = events::pop() {
+                handlers.dispatch(event);
+            }
+        }
+    }
+    logging::flush_screen();
+}
+
+/// The default cpu loop: handles the queued events and the work queued for the bootstrap
+/// processor (see [smp::run_on]), then halts until the next interrupt.
+pub fn event_loop() -> ! {
+    loop {
+        run_pending_events();
+        smp::run_pending_work();
+
+        // Check for new events with interrupts disabled; `sti; hlt` then cannot miss one
+        // arriving between the check and the halt.
+        x86_64::instructions::interrupts::disable();
+        if events::is_empty() && !smp::has_pending_work() {
+            x86_64::instructions::interrupts::enable_and_hlt();
+        } else {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
     }
 }
 
-#[panic_handler]
+/// Draws the panic report on the screen, see [set_panic_screen].
+static PANIC_SCREEN: spin::Once<fn(&str)> = spin::Once::new();
+static PANICKING: AtomicBool = AtomicBool::new(false);
+/// The panic report is formatted here, since the heap may be what failed.
+static PANIC_REPORT: RacyCell<[u8; 4096]> = RacyCell::new([0; 4096]);
+
+/// Installs the function that shows the panic report on the screen (a "blue screen").
+/// It runs with interrupts disabled and must not allocate. Only the first call has any effect.
+pub fn set_panic_screen(panic_screen: fn(&str)) {
+    PANIC_SCREEN.call_once(|| panic_screen);
+}
+
+/// Formats into a fixed buffer, silently dropping whatever does not fit.
+struct ReportWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl ReportWriter<'_> {
+    fn as_str(&self) -> &str {
+        // A multibyte character may have been cut off at the end.
+        match core::str::from_utf8(&self.buffer[..self.len]) {
+            Ok(report) => report,
+            Err(error) => unsafe { core::str::from_utf8_unchecked(&self.buffer[..error.valid_up_to()]) },
+        }
+    }
+}
+
+impl fmt::Write for ReportWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+// Host unit tests (see [pixel]) use std's panic handler
+#[cfg_attr(not(test), panic_handler)]
+#[cfg_attr(test, allow(dead_code))]
 fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    // The panic may have happened while the port was locked; we are not going back there.
+    unsafe { SERIAL.force_unlock() };
+    let backtrace = backtrace::Backtrace::here();
     let _ = writeln!(serial(), "PANIC: {info}");
+    let _ = write!(serial(), "{backtrace}");
+
+    // Skip the screen if drawing the previous panic report panicked too.
+    if !PANICKING.swap(true, Ordering::Relaxed) {
+        if let Some(panic_screen) = PANIC_SCREEN.get() {
+            let mut report = ReportWriter { buffer: unsafe { PANIC_REPORT.get_mut() }, len: 0 };
+            let _ = write!(report, "{}", info.message());
+            if let Some(location) = info.location() {
+                let _ = write!(report, "\n\nat {location}");
+            }
+            let _ = write!(report, "\n\n{backtrace}");
+            panic_screen(report.as_str());
+        }
+    }
     hlt_loop();
 }
 
@@ -0,0 +1,152 @@
+//! Backend for the [log](https://docs.rs/log) crate.
+//!
+//! Every record at or above the level passed to [init] is written to the serial port,
+//! prefixed with the timer tick it was logged at. A second sink, usually the on-screen
+//! console, can be added with [set_screen_sink]; it has its own (typically stricter) level
+//! so the screen is not flooded with debug output.
+//!
+//! Records can be logged anywhere, including interrupt and exception handlers and the other
+//! CPUs, while the screen is only drawn from the bootstrap processor's CPU loop. So records
+//! for the screen sink are queued as text, and [flush_screen] hands them to the sink from
+//! there (it is called by [crate::run_pending_events]).
+//!
+//! ```ignore
+//! kernel::logging::init(log::LevelFilter::Info);
+//! log::info!("Heap initialized");
+//! ```
+
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{serial, ticks};
+
+/// Used when the requested level cannot be parsed.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Bytes of text queued for the screen sink at most; records that do not fit are dropped.
+const SCREEN_QUEUE_SIZE: usize = 4096;
+
+struct ScreenSink {
+    write: fn(fmt::Arguments),
+    level: LevelFilter,
+}
+
+static SCREEN_SINK: Mutex<Option<ScreenSink>> = Mutex::new(None);
+static SCREEN_QUEUE: Mutex<ScreenQueue> = Mutex::new(ScreenQueue { text: [0; SCREEN_QUEUE_SIZE], len: 0, dropped: 0 });
+
+/// Records formatted for the screen sink and waiting for [flush_screen].
+struct ScreenQueue {
+    text: [u8; SCREEN_QUEUE_SIZE],
+    len: usize,
+    /// Records dropped since the last flush because the queue was full
+    dropped: u64,
+}
+
+impl fmt::Write for ScreenQueue {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let text = self.text.get_mut(self.len..self.len + s.len()).ok_or(fmt::Error)?;
+        text.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let _ = writeln!(serial(), "[{:>8}] {:<5} {}: {}",
+                         ticks(), record.level(), record.target(), record.args());
+
+        without_interrupts(|| {
+            let screen_level = SCREEN_SINK.lock().as_ref().map(|sink| sink.level);
+            if screen_level.is_some_and(|level| record.level() <= level) {
+                let mut queue = SCREEN_QUEUE.lock();
+                let len = queue.len;
+                if writeln!(queue, "{}: {}", level_label(record.level()), record.args()).is_err() {
+                    // Keep only whole records
+                    queue.len = len;
+                    queue.dropped += 1;
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+fn level_label(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[91mERROR\x1b[0m",
+        Level::Warn => "\x1b[93mWARN\x1b[0m",
+        Level::Info => "INFO",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
+
+/// Installs the kernel logger. Records below `level` are discarded.
+/// Only the first call has any effect.
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+/// Parses a level name such as `"debug"` (case-insensitive), falling back to [DEFAULT_LEVEL].
+/// Handy for a level chosen at build time, e.g. `parse_level(option_env!("KERNEL_LOG"))`.
+pub fn parse_level(name: Option<&str>) -> LevelFilter {
+    name.and_then(|name| LevelFilter::from_str(name).ok()).unwrap_or(DEFAULT_LEVEL)
+}
+
+/// Changes the level below which records are discarded.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+/// Also sends records at or above `level` to `write`, e.g. a function printing to the
+/// screen. ANSI colour escapes are used for warnings and errors. `write` is only called by
+/// [flush_screen].
+pub fn set_screen_sink(write: fn(fmt::Arguments), level: LevelFilter) {
+    without_interrupts(|| {
+        *SCREEN_SINK.lock() = Some(ScreenSink { write, level });
+    });
+}
+
+/// Hands the records queued since the last call to the screen sink. Must only be called
+/// where the sink may draw, which for the on-screen console is the bootstrap processor
+/// outside of interrupt handlers.
+pub fn flush_screen() {
+    let mut text = [0; SCREEN_QUEUE_SIZE];
+    // Copy the queue and the sink out so they are not locked while the sink runs; it may log
+    // itself.
+    let (len, dropped, write) = without_interrupts(|| {
+        let mut queue = SCREEN_QUEUE.lock();
+        let len = core::mem::take(&mut queue.len);
+        text[..len].copy_from_slice(&queue.text[..len]);
+        (len, core::mem::take(&mut queue.dropped), SCREEN_SINK.lock().as_ref().map(|sink| sink.write))
+    });
+    let Some(write) = write else {
+        return;
+    };
+    if len > 0 {
+        // Only whole records are queued, so the text is valid UTF-8
+        write(format_args!("{}", core::str::from_utf8(&text[..len]).unwrap_or_default()));
+    }
+    if dropped > 0 {
+        write(format_args!("({} log records dropped)\n", dropped));
+    }
+}
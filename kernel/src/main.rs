@@ -8,113 +8,210 @@ extern crate alloc;
 mod screen;
 mod allocator;
 mod frame_allocator;
-mod interrupts;
-mod gdt;
 mod pong;
+mod shell;
 
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
 use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
 use bootloader_api::config::Mapping::Dynamic;
-use bootloader_api::info::MemoryRegionKind;
-use kernel::{HandlerTable, serial};
-use pc_keyboard::DecodedKey;
-use x86_64::VirtAddr;
-use crate::frame_allocator::BootInfoFrameAllocator;
+use kernel::{backtrace, gdt, interrupts, logging, smp, HandlerTable, serial};
+use log::{debug, info, LevelFilter};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::frame_allocator::{FRAME_ALLOCATOR, MAPPER};
 use crate::pong::PongGame;
 use crate::screen::Writer;
+use crate::shell::Shell;
+
+/// Size of the kernel stack. The page below it is left unmapped as a guard page.
+const KERNEL_STACK_SIZE: u64 = 256 * 1024;
 
 const BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Dynamic); // obtain physical memory offset
-    config.kernel_stack_size = 256 * 1024; // 256 KiB kernel stack size
+    config.kernel_stack_size = KERNEL_STACK_SIZE;
     config
 };
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
-// Global game state
-static mut GAME: Option<PongGame> = None;
+/// Pong is updated and redrawn at 60 frames per second
+const FRAME_PERIOD: Duration = Duration::from_nanos(1_000_000_000 / 60);
+/// The console cursor is shown and hidden at this period
+const CURSOR_BLINK_PERIOD: Duration = Duration::from_millis(500);
+/// Lines PageUp and PageDown scroll the console by
+const SCROLL_LINES: usize = 10;
+
+/// Whether the text console is shown instead of Pong, toggled with F4. Pong pauses meanwhile.
+static CONSOLE_SHOWN: AtomicBool = AtomicBool::new(false);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    writeln!(serial(), "Entered kernel with boot info: {boot_info:?}").unwrap();
-    writeln!(serial(), "Frame Buffer: {:p}", boot_info.framebuffer.as_ref().unwrap().buffer()).unwrap();
+    let entry_stack_pointer = gdt::stack_pointer();
+    logging::init(logging::parse_level(option_env!("KERNEL_LOG")));
+    backtrace::init(boot_info.kernel_image_offset, entry_stack_pointer.align_up(4096u64), KERNEL_STACK_SIZE);
+    debug!("Entered kernel with boot info: {boot_info:?}");
+    debug!("Frame Buffer: {:p}", boot_info.framebuffer.as_ref().unwrap().buffer());
 
     let frame_info = boot_info.framebuffer.as_ref().unwrap().info();
     let framebuffer = boot_info.framebuffer.as_mut().unwrap();
     screen::init(framebuffer);
+    kernel::set_panic_screen(screen::panic_screen);
     
-    writeln!(serial(), "Screen initialized with dimensions: {}x{}", 
-             frame_info.width, frame_info.height).unwrap();
+    info!("Screen initialized with dimensions: {}x{}", frame_info.width, frame_info.height);
 
     for r in boot_info.memory_regions.iter() {
-        writeln!(serial(), "{:?} {:?} {:?} {}", r, r.start as *mut u8, r.end as *mut usize, r.end-r.start).unwrap();
+        debug!("{:?} {:?} {:?} {}", r, r.start as *mut u8, r.end as *mut usize, r.end-r.start);
     }
 
-    let usable_region = boot_info.memory_regions.iter().filter(|x|x.kind == MemoryRegionKind::Usable).last().unwrap();
-    writeln!(serial(), "{usable_region:?}").unwrap();
-
     let physical_offset = boot_info.physical_memory_offset.take().expect("Failed to find physical memory offset");
-    let ptr = (physical_offset + usable_region.start) as *mut u8;
-    writeln!(serial(), "Physical memory offset: {:X}; usable range: {:p}", physical_offset, ptr).unwrap();
-
-    // Initialize heap allocator
-    allocator::init_heap((physical_offset + usable_region.start) as usize);
-
+    debug!("Physical memory offset: {:X}", physical_offset);
     let rsdp = boot_info.rsdp_addr.take();
-    let mut mapper = frame_allocator::init(VirtAddr::new(physical_offset));
-    let mut frame_allocator = BootInfoFrameAllocator::new(&boot_info.memory_regions);
-    
-    gdt::init();
+
+    // Initialize paging and the frame allocator, then map the heap with frames from it
+    frame_allocator::init(VirtAddr::new(physical_offset), &boot_info.memory_regions);
+    // The application processors start in real mode, so their start-up code must lie below 1 MiB
+    let ap_trampoline = FRAME_ALLOCATOR.lock().as_mut().unwrap().allocate_frame_below(PhysAddr::new(0x10_0000));
+    allocator::init_heap().expect("Heap initialization failed");
+    screen::screenwriter().set_scrollback_lines(500);
+    // The console keeps a heap scrollback, so only log to the screen once the heap exists
+    logging::set_screen_sink(screen::write_log, LevelFilter::Info);
+    info!("Physical memory: {}", frame_allocator::stats().unwrap());
+
+    {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let mapper = mapper.as_mut().unwrap();
+        gdt::init(mapper, frame_allocator.as_mut().unwrap()).expect("Failed to map the IST stacks");
+        gdt::guard_kernel_stack(entry_stack_pointer, KERNEL_STACK_SIZE, mapper);
+    }
 
     // Test heap allocation
     let x = Box::new(42);
     let y = Box::new(24);
+    // Show the records logged so far before writing to the console directly
+    logging::flush_screen();
     writeln!(Writer, "Heap allocation works: {} + {} = {}", *x, *y, *x + *y).unwrap();
+    debug!("Heap statistics:\n{}", allocator::stats());
     
-    writeln!(serial(), "Starting kernel and initializing Pong game...").unwrap();
+    info!("Starting kernel and initializing Pong game...");
     
-    // Initialize Pong game
-    unsafe {
-        GAME = Some(PongGame::new(frame_info.width as usize, frame_info.height as usize));
+    // Pong redraws the whole screen every tick, so draw into a back buffer to avoid flicker
+    screen::screenwriter().enable_double_buffering();
+
+    // Initialize Pong game, shared by the handlers below and the debug shell
+    let game = Arc::new(Mutex::new(PongGame::new(frame_info.width as usize, frame_info.height as usize)));
+    let mut shell = Shell::new(&boot_info.memory_regions, game.clone());
+
+    let lapic_ptr = {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        interrupts::init_interrupt_controller(rsdp.map(|rsdp| rsdp as usize), physical_offset,
+                                              mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap())
+    };
+    // The heap cannot grow while the page tables are locked, so allocate for the application
+    // processors before starting them
+    smp::init();
+    {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        smp::start_application_processors(ap_trampoline, mapper.as_mut().unwrap(), frame_allocator.as_mut().unwrap());
+    }
+    // Tick faster than the game renders, so the software timers fire close to their deadlines
+    let key_game = game.clone();
+    let frame_game = game.clone();
+    let handlers = HandlerTable::new()
+        .keyboard(move |key| handle_key(&key_game, key))
+        .timer_frequency(240)
+        .periodic(FRAME_PERIOD, move || {
+            if !CONSOLE_SHOWN.load(Ordering::Relaxed) {
+                let mut game = frame_game.lock();
+                game.update(kernel::uptime());
+                game.render();
+            }
+        })
+        .periodic(CURSOR_BLINK_PERIOD, || {
+            if CONSOLE_SHOWN.load(Ordering::Relaxed) {
+                let writer = screen::screenwriter();
+                writer.blink_cursor();
+                writer.present();
+            }
+        })
+        .serial(move |byte| shell.handle_byte(byte))
+        .startup(move || start(&game));
+    #[cfg(feature = "stack-overflow-test")]
+    let handlers = handlers.cpu_loop(stack_overflow_test);
+    handlers.start(lapic_ptr)
+}
+
+/// Overflows the kernel stack once interrupts are set up. Expected to end in a double fault
+/// report on serial rather than a triple fault; the host runner checks for it.
+#[cfg(feature = "stack-overflow-test")]
+fn stack_overflow_test() -> ! {
+    #[allow(unconditional_recursion)]
+    fn recurse(depth: u64) -> u64 {
+        // black_box keeps the recursion from being turned into a loop
+        core::hint::black_box(recurse(depth + 1)) + depth
     }
 
-    let lapic_ptr = interrupts::init_apic(rsdp.expect("Failed to get RSDP address") as usize, physical_offset, &mut mapper, &mut frame_allocator);
-    HandlerTable::new()
-        .keyboard(key)
-        .timer(tick)
-        .startup(start)
-        .start(lapic_ptr)
+    info!("stack-overflow-test: overflowing the kernel stack");
+    recurse(0);
+    panic!("stack-overflow-test: recursion returned");
 }
 
-fn start() {
-    writeln!(serial(), "Welcome to Pong OS!").unwrap();
-    writeln!(serial(), "Use Up/Down arrows to move your paddle").unwrap();
-    writeln!(serial(), "First to 5 points wins!").unwrap();
+fn start(game: &Mutex<PongGame>) {
+    info!("Welcome to Pong OS!");
+    info!("Use Up/Down arrows to move your paddle");
+    info!("First to 5 points wins! F3 shows the frame rate");
+    info!("F4 switches to this console and back, PageUp/PageDown scroll it");
     
-    // Initial render of the game using raw pointer
-    unsafe {
-        let game_ptr = &raw const GAME;
-        if let Some(game) = &*game_ptr {
-            game.render();
-        }
-    }
+    // Initial render of the game
+    game.lock().render();
+
+    info!("Debug shell listening on serial, type 'help' for commands");
+    shell::prompt();
 }
 
-fn tick() {
-    unsafe {
-        let game_ptr = &raw mut GAME;
-        if let Some(game) = &mut *game_ptr {
-            game.update();
-            game.render();
+fn handle_key(game: &Mutex<PongGame>, key: DecodedKey) {
+    match key {
+        // F1 dumps heap statistics and the tracked live allocations over serial,
+        // F2 toggles allocation tracking.
+        DecodedKey::RawKey(KeyCode::F1) => {
+            writeln!(serial(), "Heap statistics:\n{}", allocator::stats()).unwrap();
+            allocator::dump_allocations(&mut serial()).unwrap();
+        }
+        DecodedKey::RawKey(KeyCode::F2) => {
+            let enabled = !allocator::tracking_enabled();
+            allocator::set_tracking(enabled);
+            info!("Allocation tracking {}", if enabled { "enabled" } else { "disabled" });
+        }
+        DecodedKey::RawKey(KeyCode::F4) => toggle_console(),
+        DecodedKey::RawKey(KeyCode::PageUp) if CONSOLE_SHOWN.load(Ordering::Relaxed) => {
+            scroll_console(|offset| offset + SCROLL_LINES);
         }
+        DecodedKey::RawKey(KeyCode::PageDown) if CONSOLE_SHOWN.load(Ordering::Relaxed) => {
+            scroll_console(|offset| offset.saturating_sub(SCROLL_LINES));
+        }
+        _ => game.lock().handle_key(key),
     }
 }
 
-fn key(key: DecodedKey) {
-    unsafe {
-        let game_ptr = &raw mut GAME;
-        if let Some(game) = &mut *game_ptr {
-            game.handle_key(key);
-        }
+/// Switches between Pong and the console. Pong has drawn over the console, so it is redrawn
+/// from the scrollback.
+fn toggle_console() {
+    let shown = !CONSOLE_SHOWN.fetch_xor(true, Ordering::Relaxed);
+    if shown {
+        scroll_console(|_| 0);
     }
-}
\ No newline at end of file
+}
+
+/// Scrolls the console view to the number of lines back `offset` computes from the current
+/// one, and shows it.
+fn scroll_console(offset: impl FnOnce(usize) -> usize) {
+    let writer = screen::screenwriter();
+    writer.scroll_view(offset(writer.view_offset()));
+    writer.present();
+}
@@ -0,0 +1,113 @@
+//! The legacy 8259 PICs, used instead of the APICs when the ACPI tables are missing or do not
+//! list an APIC (for example with QEMU's `-machine isapc`).
+//!
+//! There are two chained PICs with eight lines each, the second one on line 2 of the first.
+//! They are remapped so that line n raises vector [crate::irq::FIRST_VECTOR] + n, which makes
+//! the lines the ISA IRQs [crate::irq::register_irq] hands out. The PIT on line 0 drives the
+//! timer tick in place of the LAPIC timer.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+use crate::irq;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+/// Number of interrupt lines of both PICs together.
+pub const LINES: u8 = 16;
+/// The master line the slave PIC is connected to.
+const CASCADE_LINE: u8 = 2;
+
+const ICW1_INIT: u8 = 0x11; // Initialization, ICW4 follows
+const ICW4_8086: u8 = 0x01;
+const OCW3_READ_ISR: u8 = 0x0B;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the PICs deliver the device interrupts, see [init].
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Remaps both PICs to start at [irq::FIRST_VECTOR], with every line masked except the cascade.
+pub(crate) fn init() {
+    unsafe {
+        let mut master_command = Port::<u8>::new(MASTER_COMMAND);
+        let mut master_data = Port::<u8>::new(MASTER_DATA);
+        let mut slave_command = Port::<u8>::new(SLAVE_COMMAND);
+        let mut slave_data = Port::<u8>::new(SLAVE_DATA);
+
+        master_command.write(ICW1_INIT);
+        io_wait();
+        slave_command.write(ICW1_INIT);
+        io_wait();
+        master_data.write(irq::FIRST_VECTOR);
+        io_wait();
+        slave_data.write(irq::FIRST_VECTOR + 8);
+        io_wait();
+        master_data.write(1 << CASCADE_LINE); // Slave on line 2
+        io_wait();
+        slave_data.write(CASCADE_LINE); // Its cascade identity
+        io_wait();
+        master_data.write(ICW4_8086);
+        io_wait();
+        slave_data.write(ICW4_8086);
+        io_wait();
+
+        master_data.write(!(1 << CASCADE_LINE));
+        slave_data.write(0xFF);
+    }
+    ACTIVE.store(true, Ordering::Relaxed);
+}
+
+/// Masks every line, for when the APICs handle the interrupts.
+pub(crate) fn disable() {
+    unsafe {
+        Port::<u8>::new(MASTER_DATA).write(0xFF);
+        Port::<u8>::new(SLAVE_DATA).write(0xFF);
+    }
+    ACTIVE.store(false, Ordering::Relaxed);
+}
+
+/// Stops `line` from raising interrupts.
+pub fn mask(line: u8) {
+    update_mask(line, |mask, bit| mask | bit);
+}
+
+/// Lets `line` raise interrupts.
+pub fn unmask(line: u8) {
+    update_mask(line, |mask, bit| mask & !bit);
+}
+
+fn update_mask(line: u8, change: impl FnOnce(u8, u8) -> u8) {
+    let (mut port, bit) = if line < 8 {
+        (Port::<u8>::new(MASTER_DATA), 1 << line)
+    } else {
+        (Port::<u8>::new(SLAVE_DATA), 1 << (line - 8))
+    };
+    unsafe {
+        let mask = port.read();
+        port.write(change(mask, bit));
+    }
+}
+
+/// Signals the end of the interrupt being handled. Interrupts do not nest, so if the cascade
+/// line is in service the interrupt came from the slave, which needs its own end of interrupt.
+pub(crate) fn end_interrupt() {
+    unsafe {
+        let mut master_command = Port::<u8>::new(MASTER_COMMAND);
+        master_command.write(OCW3_READ_ISR);
+        if master_command.read() & (1 << CASCADE_LINE) != 0 {
+            Port::<u8>::new(SLAVE_COMMAND).write(END_OF_INTERRUPT);
+        }
+        master_command.write(END_OF_INTERRUPT);
+    }
+}
+
+/// Gives the PICs time to handle the previous write on old hardware.
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
@@ -0,0 +1,113 @@
+//! Encoding of colours for the framebuffer layouts the bootloader can hand over.
+//!
+//! This module only does arithmetic, so its tests run on the host, see the README.
+
+use bootloader_api::info::{FrameBufferInfo, PixelFormat};
+
+/// An RGB colour.
+pub type Color = (u8, u8, u8);
+
+/// Encodes colours for one framebuffer layout. Built once from the `FrameBufferInfo`, which
+/// turns the pixel format into bit positions, so drawing does not re-inspect the format for
+/// every pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelEncoder {
+    layout: Layout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Layout {
+    /// Each 8-bit channel shifted to its bit position inside a little-endian word. Channels
+    /// shifted out of the word are dropped.
+    Channels { red_shift: u32, green_shift: u32, blue_shift: u32 },
+    /// A single grey value
+    Grey,
+}
+
+impl PixelEncoder {
+    pub fn new(info: &FrameBufferInfo) -> Self {
+        Self::for_format(info.pixel_format)
+    }
+
+    pub fn for_format(format: PixelFormat) -> Self {
+        let channels = |red_shift, green_shift, blue_shift| Layout::Channels { red_shift, green_shift, blue_shift };
+        let layout = match format {
+            PixelFormat::Rgb => channels(0, 8, 16),
+            PixelFormat::Bgr => channels(16, 8, 0),
+            PixelFormat::U8 => Layout::Grey,
+            PixelFormat::Unknown { red_position, green_position, blue_position } =>
+                channels(red_position as u32, green_position as u32, blue_position as u32),
+            // Formats added to bootloader_api later: draw as greyscale rather than panicking
+            _ => Layout::Grey,
+        };
+        Self { layout }
+    }
+
+    /// Returns the first four framebuffer bytes of a pixel with the given colour, see
+    /// [encode_pixel].
+    pub fn encode(&self, (r, g, b): Color) -> [u8; 4] {
+        match self.layout {
+            Layout::Channels { red_shift, green_shift, blue_shift } => {
+                let channel = |value: u8, shift: u32| (value as u32).checked_shl(shift).unwrap_or(0);
+                (channel(r, red_shift) | channel(g, green_shift) | channel(b, blue_shift)).to_le_bytes()
+            }
+            Layout::Grey => {
+                let luma = (r as u32 * 77 + g as u32 * 150 + b as u32 * 29) >> 8;
+                [luma as u8, 0, 0, 0]
+            }
+        }
+    }
+}
+
+/// Returns the first four framebuffer bytes of a pixel with the given colour. Callers write
+/// as many of them as the framebuffer's `bytes_per_pixel` (at most four carry colour).
+///
+/// - `Rgb` / `Bgr`: one byte per channel in that order.
+/// - `U8`: a single grey value, using the integer BT.601 luma weights.
+/// - `Unknown`: each 8-bit channel shifted to its bit position inside a little-endian word.
+pub fn encode_pixel(format: PixelFormat, color: Color) -> [u8; 4] {
+    PixelEncoder::for_format(format).encode(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COLOR: Color = (0x12, 0x34, 0x56);
+
+    #[test]
+    fn rgb() {
+        assert_eq!(encode_pixel(PixelFormat::Rgb, COLOR), [0x12, 0x34, 0x56, 0]);
+    }
+
+    #[test]
+    fn bgr() {
+        assert_eq!(encode_pixel(PixelFormat::Bgr, COLOR), [0x56, 0x34, 0x12, 0]);
+    }
+
+    #[test]
+    fn u8_is_luma() {
+        assert_eq!(encode_pixel(PixelFormat::U8, (0, 0, 0)), [0, 0, 0, 0]);
+        assert_eq!(encode_pixel(PixelFormat::U8, (255, 255, 255)), [255, 0, 0, 0]);
+        // Green weighs most, blue least
+        let [red, ..] = encode_pixel(PixelFormat::U8, (255, 0, 0));
+        let [green, ..] = encode_pixel(PixelFormat::U8, (0, 255, 0));
+        let [blue, ..] = encode_pixel(PixelFormat::U8, (0, 0, 255));
+        assert_eq!((red, green, blue), (76, 149, 28));
+    }
+
+    #[test]
+    fn unknown_uses_bit_positions() {
+        let format = PixelFormat::Unknown { red_position: 24, green_position: 16, blue_position: 8 };
+        assert_eq!(encode_pixel(format, COLOR), [0, 0x56, 0x34, 0x12]);
+        // Like Bgr
+        let format = PixelFormat::Unknown { red_position: 16, green_position: 8, blue_position: 0 };
+        assert_eq!(encode_pixel(format, COLOR), encode_pixel(PixelFormat::Bgr, COLOR));
+    }
+
+    #[test]
+    fn unknown_drops_channels_outside_the_word() {
+        let format = PixelFormat::Unknown { red_position: 0, green_position: 40, blue_position: 255 };
+        assert_eq!(encode_pixel(format, COLOR), [0x12, 0, 0, 0]);
+    }
+}
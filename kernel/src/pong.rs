@@ -1,6 +1,14 @@
-use core::fmt::Write;
+use alloc::format;
+use core::time::Duration;
+use kernel::time::FrameCounter;
 use pc_keyboard::{DecodedKey, KeyCode};
-use crate::screen::{screenwriter, Writer};
+use crate::screen::{screenwriter, Color, FontWeight, RasterHeight, ScreenWriter};
+
+/// The game advances in fixed steps of this length, however often `update` is called, so the
+/// ball moves at the same speed whatever the timer frequency is.
+const STEP: Duration = Duration::from_nanos(1_000_000_000 / 60);
+/// Steps simulated at most per `update`, so a long stall does not freeze the game catching up.
+const MAX_STEPS_PER_UPDATE: u32 = 5;
 
 pub struct PongGame {
     // Screen dimensions
@@ -27,21 +35,30 @@ pub struct PongGame {
     ball_size: usize,
     ball_velocity_x: isize,
     ball_velocity_y: isize,
+    /// Horizontal speed the ball is served with, in pixels per step
+    ball_speed: isize,
     
     // Game state
     player_score: usize,
     computer_score: usize,
     game_over: bool,
     
+    // Timing
+    last_update: Option<Duration>,
+    unsimulated: Duration,
+    frame_counter: FrameCounter,
+    /// Shows frame rate and frame time in the corner, toggled with F3
+    show_debug_overlay: bool,
+    
     // Player movement history for delayed follower
     player_position_history: [usize; 30],
     history_index: usize,
     
     // Colors
-    background_color: (u8, u8, u8),
-    paddle_color: (u8, u8, u8),
-    ball_color: (u8, u8, u8),
-    text_color: (u8, u8, u8),
+    background_color: Color,
+    paddle_color: Color,
+    ball_color: Color,
+    text_color: Color,
 }
 
 impl PongGame {
@@ -72,11 +89,17 @@ impl PongGame {
             // Increased ball velocity for faster movement
             ball_velocity_x: 35,  
             ball_velocity_y: 30,  
+            ball_speed: 6,
             
             player_score: 0,
             computer_score: 0,
             game_over: false,
             
+            last_update: None,
+            unsimulated: Duration::ZERO,
+            frame_counter: FrameCounter::new(),
+            show_debug_overlay: false,
+            
             // New: Initialize position history with current position
             player_position_history: [height / 2 - player_paddle_height / 2; 30],
             history_index: 0,
@@ -99,7 +122,7 @@ impl PongGame {
         
         // Reset velocity with slight randomization
         let direction = if self.player_score > self.computer_score { -1 } else { 1 };
-        self.ball_velocity_x = direction * 6;  // Increased from 2 to 6
+        self.ball_velocity_x = direction * self.ball_speed;
         self.ball_velocity_y = if self.ball_y % 2 == 0 { 3 } else { -3 };  // Increased from 1 to 3
         
         // Reset game state
@@ -117,6 +140,13 @@ impl PongGame {
         self.reset();
     }
     
+    /// Sets the horizontal ball speed in pixels per step. Takes effect immediately,
+    /// keeping the current direction of the ball.
+    pub fn set_ball_speed(&mut self, speed: usize) {
+        self.ball_speed = speed.max(1) as isize;
+        self.ball_velocity_x = self.ball_velocity_x.signum() * self.ball_speed;
+    }
+    
     pub fn handle_key(&mut self, key: DecodedKey) {
         match key {
             DecodedKey::RawKey(KeyCode::ArrowUp) => {
@@ -135,6 +165,9 @@ impl PongGame {
                     self.player_paddle_y = self.height - self.player_paddle_height;
                 }
             },
+            DecodedKey::RawKey(KeyCode::F3) => {
+                self.show_debug_overlay = !self.show_debug_overlay;
+            },
             DecodedKey::Unicode(' ') if self.game_over => {
                 // Restart game
                 self.new_game();
@@ -143,7 +176,20 @@ impl PongGame {
         }
     }
     
-    pub fn update(&mut self) {
+    /// Advances the game to `now` (e.g. `kernel::uptime()`), running one physics step for
+    /// every `STEP` that passed since the previous call.
+    pub fn update(&mut self, now: Duration) {
+        let elapsed = now.saturating_sub(self.last_update.unwrap_or(now));
+        self.last_update = Some(now);
+        self.unsimulated = (self.unsimulated + elapsed).min(STEP * MAX_STEPS_PER_UPDATE);
+        
+        while self.unsimulated >= STEP {
+            self.unsimulated -= STEP;
+            self.step();
+        }
+    }
+    
+    fn step(&mut self) {
         if self.game_over {
             return;
         }
@@ -252,43 +298,41 @@ impl PongGame {
         }
     }
     
-    pub fn render(&self) {
+    /// Draws a line of text horizontally centred on the screen.
+    fn draw_centered(&self, text: &str, y: usize, size: RasterHeight) {
+        let (width, _) = ScreenWriter::measure_text(text, FontWeight::Regular, size);
+        screenwriter().draw_text(self.width.saturating_sub(width) / 2, y, text, self.text_color, None, FontWeight::Regular, size);
+    }
+    
+    pub fn render(&mut self) {
+        self.frame_counter.frame();
         let writer = screenwriter();
         
-        // Clear screen
-        writer.clear();
+        // Clear the back buffer; nothing reaches the screen until present() below
+        writer.fill_rect(0, 0, self.width, self.height, self.background_color);
         
         // Draw middle line
         for y in (0..self.height).step_by(10) {
-            for i in 0..5 {
-                writer.draw_pixel(self.width / 2, y + i, 50, 50, 50);
-            }
+            writer.draw_line(self.width / 2, y, self.width / 2, y + 4, (50, 50, 50));
         }
         
         // Draw player paddle
-        for y in self.player_paddle_y..(self.player_paddle_y + self.player_paddle_height) {
-            for x in self.player_paddle_x..(self.player_paddle_x + self.player_paddle_width) {
-                writer.draw_pixel(x, y, self.paddle_color.0, self.paddle_color.1, self.paddle_color.2);
-            }
-        }
+        writer.fill_rect(self.player_paddle_x, self.player_paddle_y,
+                         self.player_paddle_width, self.player_paddle_height, self.paddle_color);
         
         // Draw computer paddle
-        for y in self.computer_paddle_y..(self.computer_paddle_y + self.computer_paddle_height) {
-            for x in self.computer_paddle_x..(self.computer_paddle_x + self.computer_paddle_width) {
-                writer.draw_pixel(x, y, self.paddle_color.0, self.paddle_color.1, self.paddle_color.2);
-            }
-        }
+        writer.fill_rect(self.computer_paddle_x, self.computer_paddle_y,
+                         self.computer_paddle_width, self.computer_paddle_height, self.paddle_color);
         
         // Draw ball
-        for y in self.ball_y..(self.ball_y + self.ball_size) {
-            for x in self.ball_x..(self.ball_x + self.ball_size) {
-                writer.draw_pixel(x, y, self.ball_color.0, self.ball_color.1, self.ball_color.2);
-            }
-        }
+        let radius = self.ball_size / 2;
+        writer.fill_circle(self.ball_x + radius, self.ball_y + radius, radius, self.ball_color);
         
-        // Draw scores
-        writer.write_pixel(self.width / 4, 20, 255);
-        writeln!(Writer, "{}                           {}", self.player_score, self.computer_score).unwrap();
+        // Draw scores above each half of the court
+        writer.draw_text(self.width / 4, 20, &format!("{}", self.player_score),
+                         self.text_color, None, FontWeight::Bold, RasterHeight::Size32);
+        writer.draw_text(self.width * 3 / 4, 20, &format!("{}", self.computer_score),
+                         self.text_color, None, FontWeight::Bold, RasterHeight::Size32);
         
         // Draw game over message if applicable
         if self.game_over {
@@ -298,10 +342,17 @@ impl PongGame {
                 "Computer Wins!"
             };
             
-            writer.write_pixel(self.width / 2 - 40, self.height / 2 - 20, 255);
-            writeln!(Writer, "{}", message).unwrap();
-            writer.write_pixel(self.width / 2 - 100, self.height / 2, 255);
-            writeln!(Writer, "Press SPACE to play again").unwrap();
+            self.draw_centered(message, self.height / 2 - 40, RasterHeight::Size32);
+            self.draw_centered("Press SPACE to play again", self.height / 2, RasterHeight::Size20);
+        }
+        
+        if self.show_debug_overlay {
+            let frame_time = self.frame_counter.frame_time().as_micros();
+            let overlay = format!("{} FPS  {}.{} ms", self.frame_counter.fps(), frame_time / 1000, frame_time / 100 % 10);
+            writer.draw_text(4, 4, &overlay, self.text_color, None, FontWeight::Regular, RasterHeight::Size16);
         }
+
+        // Show the finished frame in one go
+        writer.present();
     }
-}
\ No newline at end of file
+}
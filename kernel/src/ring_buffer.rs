@@ -0,0 +1,61 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A fixed-capacity single-producer, single-consumer queue that needs no lock, so an
+/// interrupt handler can push while the interrupted code is in the middle of popping.
+///
+/// One slot is kept empty to tell a full queue from an empty one, so it holds `N - 1` items.
+pub struct RingBuffer<T: Copy, const N: usize> {
+    slots: UnsafeCell<[MaybeUninit<T>; N]>,
+    /// Next slot to read.
+    head: AtomicUsize,
+    /// Next slot to write.
+    tail: AtomicUsize,
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: UnsafeCell::new([MaybeUninit::uninit(); N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Appends a value. Returns it back if the queue is full.
+    /// Only one context may push at a time.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % N;
+        if next == self.head.load(Ordering::Acquire) {
+            return Err(value);
+        }
+        unsafe { (*self.slots.get())[tail].write(value) };
+        self.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    /// Removes the oldest value. Only one context may pop at a time.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*self.slots.get())[head].assume_init() };
+        self.head.store((head + 1) % N, Ordering::Release);
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,18 +1,40 @@
 // Original code from rust-osdev/bootloader crate https://github.com/rust-osdev/bootloader
 
-use core::{fmt, ptr};
-use noto_sans_mono_bitmap::{FontWeight, get_raster, RasterizedChar};
-use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{fmt, mem, ptr};
+use noto_sans_mono_bitmap::{get_raster, get_raster_width, RasterizedChar};
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo};
 use noto_sans_mono_bitmap::RasterHeight::Size16;
+pub use noto_sans_mono_bitmap::{FontWeight, RasterHeight};
+use kernel::pixel::PixelEncoder;
 use kernel::RacyCell;
 
+/// Only used on the bootstrap processor outside of interrupt handlers (log records reach it
+/// through `kernel::logging::flush_screen`), and by the panic screen.
 static WRITER: RacyCell<Option<ScreenWriter>> = RacyCell::new(None);
 pub struct Writer;
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         let writer = unsafe { WRITER.get_mut() }.as_mut().unwrap();
-        writer.write_str(s)
+        writer.write_str(s)?;
+        writer.present();
+        Ok(())
+    }
+}
+
+/// Log sink printing records on the console, see `kernel::logging::set_screen_sink`.
+/// The logger only calls it from `kernel::logging::flush_screen`.
+pub fn write_log(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut Writer, args);
+}
+
+/// Panic screen for `kernel::set_panic_screen`: shows `report` on a blue page.
+pub fn panic_screen(report: &str) {
+    if let Some(writer) = unsafe { WRITER.get_mut() }.as_mut() {
+        writer.draw_panic_page(report);
     }
 }
 
@@ -29,42 +51,251 @@ pub fn init(buffer: &'static mut FrameBuffer) {
     *unsafe { WRITER.get_mut() } = Some(writer);
 }
 
+pub use kernel::pixel::Color;
+
 /// Additional vertical space between lines
 const LINE_SPACING: usize = 0;
+/// Height of a console text line
+const LINE_HEIGHT: usize = Size16 as usize + LINE_SPACING;
+/// Width of a console character cell
+const CHAR_WIDTH: usize = get_raster_width(FontWeight::Regular, Size16);
+/// Tab stops are placed every `TAB_WIDTH` character cells
+const TAB_WIDTH: usize = 8;
+/// Height of the blinking underline cursor
+const CURSOR_HEIGHT: usize = 2;
+
+/// Console colours when no SGR colour is set (the classic green of this console).
+const DEFAULT_FG: Color = (63, 255, 127);
+const DEFAULT_BG: Color = (0, 0, 0);
+
+/// The 16 colours selected by the ANSI SGR codes 30–37/40–47 (normal) and 90–97/100–107 (bright).
+const ANSI_COLORS: [Color; 16] = [
+    (0, 0, 0), (170, 0, 0), (0, 170, 0), (170, 85, 0),
+    (0, 0, 170), (170, 0, 170), (0, 170, 170), (170, 170, 170),
+    (85, 85, 85), (255, 85, 85), (85, 255, 85), (255, 255, 85),
+    (85, 85, 255), (255, 85, 255), (85, 255, 255), (255, 255, 255),
+];
+
+/// Colours of the panic page
+const PANIC_BG: Color = (0, 0, 170);
+const PANIC_FG: Color = (255, 255, 255);
+/// Distance of the panic page's text from the edges of the screen
+const PANIC_MARGIN: usize = 32;
+
+/// Maximum number of numeric parameters kept from one escape sequence
+const MAX_ESCAPE_PARAMS: usize = 8;
+
+/// A character in the scrollback buffer together with the attributes it was written with.
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    c: char,
+    fg: Color,
+    bg: Color,
+    bold: bool,
+}
+
+/// Progress through an ANSI escape sequence.
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    /// Saw ESC
+    Start,
+    /// Inside `ESC [`, collecting `;`-separated numbers. `count` is the index of the
+    /// parameter currently being read.
+    Csi { params: [u16; MAX_ESCAPE_PARAMS], count: usize },
+}
 
 pub struct ScreenWriter {
     framebuffer: &'static mut [u8],
+    /// Off-screen copy of the framebuffer that all drawing goes to once double buffering is
+    /// enabled. Nothing becomes visible until `present` is called.
+    back_buffer: Option<Vec<u8>>,
+    /// Rows of the back buffer changed since the last `present`, as an inclusive range.
+    dirty_rows: Option<(usize, usize)>,
     info: FrameBufferInfo,
+    encoder: PixelEncoder,
     x_pos: usize,
     y_pos: usize,
+    /// Attributes for text written through `fmt::Write`, changed by SGR escape sequences.
+    fg: Color,
+    bg: Color,
+    bold: bool,
+    escape: Escape,
+    /// Finished console lines, oldest first. Holds at most `scrollback_lines` lines and stays
+    /// empty (no heap use) until `set_scrollback_lines` is called.
+    scrollback: VecDeque<Vec<Cell>>,
+    scrollback_lines: usize,
+    /// The line the cursor is on, recorded for the scrollback.
+    current_line: Vec<Cell>,
+    /// How many lines the view is scrolled back from the live console.
+    view_offset: usize,
+    cursor_visible: bool,
 }
 
 impl ScreenWriter {
     pub fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
         let mut logger = Self {
             framebuffer,
+            back_buffer: None,
+            dirty_rows: None,
             info,
+            encoder: PixelEncoder::new(&info),
             x_pos: 0,
             y_pos: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bold: false,
+            escape: Escape::None,
+            scrollback: VecDeque::new(),
+            scrollback_lines: 0,
+            current_line: Vec::new(),
+            view_offset: 0,
+            cursor_visible: false,
         };
         logger.clear();
         logger
     }
 
     fn newline(&mut self) {
-        self.y_pos += Size16 as usize + LINE_SPACING;
-        self.carriage_return()
+        self.end_line();
+        self.carriage_return();
+        if self.y_pos + 2 * LINE_HEIGHT > self.height() {
+            self.scroll_up();
+        } else {
+            self.y_pos += LINE_HEIGHT;
+        }
     }
 
     fn carriage_return(&mut self) {
         self.x_pos = 0;
     }
 
+    /// Moves everything on screen up by one text line and blanks the line the cursor is on.
+    fn scroll_up(&mut self) {
+        let row_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let visible_bytes = (self.height() * row_bytes).min(self.buffer_mut().len());
+        self.buffer_mut().copy_within(LINE_HEIGHT * row_bytes..visible_bytes, 0);
+        self.fill_rect(0, self.y_pos, self.width(), self.height() - self.y_pos, self.bg);
+        self.mark_dirty(0, self.height() - 1);
+    }
+
     /// Erases all text on the screen.
     pub fn clear(&mut self) {
         self.x_pos = 0;
         self.y_pos = 0;
-        self.framebuffer.fill(0);
+        self.cursor_visible = false;
+        self.fill_rect(0, 0, self.width(), self.height(), self.bg);
+    }
+
+    /// Keeps up to `lines` finished console lines on the heap so they can be brought back
+    /// with `scroll_view`. Zero (the default) disables the scrollback. Must not be called
+    /// before the heap is initialized.
+    pub fn set_scrollback_lines(&mut self, lines: usize) {
+        self.scrollback_lines = lines;
+        while self.scrollback.len() > lines {
+            self.scrollback.pop_front();
+        }
+    }
+
+    /// Redraws the console as it was `lines_up` lines ago, using the scrollback. Passing 0
+    /// returns to the live view, which also happens as soon as more text is written.
+    pub fn scroll_view(&mut self, lines_up: usize) {
+        if self.scrollback_lines == 0 {
+            return;
+        }
+        let rows = self.height() / LINE_HEIGHT;
+        let total = self.scrollback.len() + 1;
+        let lines_up = lines_up.min(total.saturating_sub(rows));
+        let end = total - lines_up;
+        let start = end.saturating_sub(rows);
+
+        self.view_offset = lines_up;
+        self.cursor_visible = false;
+        self.fill_rect(0, 0, self.width(), self.height(), self.bg);
+
+        let scrollback = mem::take(&mut self.scrollback);
+        let current_line = mem::take(&mut self.current_line);
+        for (row, index) in (start..end).enumerate() {
+            let line = scrollback.get(index).unwrap_or(&current_line);
+            for (column, cell) in line.iter().enumerate() {
+                self.draw_cell(column * CHAR_WIDTH, row * LINE_HEIGHT, *cell);
+            }
+        }
+        self.scrollback = scrollback;
+        self.current_line = current_line;
+
+        if lines_up == 0 {
+            self.y_pos = (end - start - 1) * LINE_HEIGHT;
+        }
+    }
+
+    /// How many lines the view is scrolled back, see `scroll_view`.
+    pub fn view_offset(&self) -> usize {
+        self.view_offset
+    }
+
+    /// Shows or hides the underline cursor at the console's write position. Call this
+    /// periodically (e.g. twice a second) to make the cursor blink. The cursor stays hidden
+    /// while the view is scrolled back.
+    pub fn blink_cursor(&mut self) {
+        if self.view_offset != 0 {
+            return;
+        }
+        if self.cursor_visible {
+            self.hide_cursor();
+        } else {
+            self.fill_rect(self.x_pos, self.y_pos + LINE_HEIGHT - CURSOR_HEIGHT, CHAR_WIDTH, CURSOR_HEIGHT, self.fg);
+            self.cursor_visible = true;
+        }
+    }
+
+    fn hide_cursor(&mut self) {
+        if self.cursor_visible {
+            self.fill_rect(self.x_pos, self.y_pos + LINE_HEIGHT - CURSOR_HEIGHT, CHAR_WIDTH, CURSOR_HEIGHT, self.bg);
+            self.cursor_visible = false;
+        }
+    }
+
+    /// Switches drawing to a heap-allocated back buffer, starting out as a copy of what is
+    /// currently on screen. From now on changes only show up when `present` is called, so a
+    /// frame can be cleared and redrawn without the intermediate states flickering.
+    pub fn enable_double_buffering(&mut self) {
+        if self.back_buffer.is_none() {
+            let mut back_buffer = vec![0; self.framebuffer.len()];
+            back_buffer.copy_from_slice(self.framebuffer);
+            self.back_buffer = Some(back_buffer);
+            self.dirty_rows = None;
+        }
+    }
+
+    /// Copies every row of the back buffer changed since the last call to the framebuffer.
+    /// Does nothing if double buffering is not enabled.
+    pub fn present(&mut self) {
+        let (Some(back_buffer), Some((first, last))) = (&self.back_buffer, self.dirty_rows.take()) else {
+            return;
+        };
+        let row_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let start = first * row_bytes;
+        let end = ((last + 1) * row_bytes).min(self.framebuffer.len());
+        self.framebuffer[start..end].copy_from_slice(&back_buffer[start..end]);
+    }
+
+    /// The buffer drawing operations write to: the back buffer if there is one, otherwise
+    /// the framebuffer itself.
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        match &mut self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.framebuffer,
+        }
+    }
+
+    fn mark_dirty(&mut self, first: usize, last: usize) {
+        if self.back_buffer.is_some() {
+            self.dirty_rows = Some(match self.dirty_rows {
+                Some((f, l)) => (f.min(first), l.max(last)),
+                None => (first, last),
+            });
+        }
     }
 
     fn width(&self) -> usize {
@@ -76,95 +307,383 @@ impl ScreenWriter {
     }
 
     fn write_char(&mut self, c: char) {
+        if self.view_offset != 0 {
+            self.scroll_view(0);
+        }
+        self.hide_cursor();
+
+        match self.escape {
+            Escape::None => {}
+            Escape::Start => {
+                self.escape = if c == '[' {
+                    Escape::Csi { params: [0; MAX_ESCAPE_PARAMS], count: 0 }
+                } else {
+                    Escape::None
+                };
+                return;
+            }
+            Escape::Csi { mut params, mut count } => {
+                match c {
+                    '0'..='9' => {
+                        if let Some(param) = params.get_mut(count) {
+                            *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                        }
+                        self.escape = Escape::Csi { params, count };
+                    }
+                    ';' => {
+                        count += 1;
+                        self.escape = Escape::Csi { params, count };
+                    }
+                    '\x40'..='\x7e' => {
+                        self.escape = Escape::None;
+                        let params = &params[..(count + 1).min(MAX_ESCAPE_PARAMS)];
+                        self.handle_csi(c, params);
+                    }
+                    _ => self.escape = Escape::None,
+                }
+                return;
+            }
+        }
+
         match c {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
+            '\t' => {
+                let column = self.x_pos / CHAR_WIDTH;
+                let next_stop = (column / TAB_WIDTH + 1) * TAB_WIDTH;
+                for _ in column..next_stop {
+                    if self.x_pos + CHAR_WIDTH > self.width() {
+                        break;
+                    }
+                    self.write_char(' ');
+                }
+            }
+            '\x08' => {
+                // Backspace: step back one cell and erase it
+                if self.x_pos >= CHAR_WIDTH {
+                    self.x_pos -= CHAR_WIDTH;
+                    self.fill_rect(self.x_pos, self.y_pos, CHAR_WIDTH, LINE_HEIGHT, self.bg);
+                    let column = self.x_pos / CHAR_WIDTH;
+                    if column + 1 == self.current_line.len() {
+                        self.current_line.pop();
+                    } else if let Some(cell) = self.current_line.get_mut(column) {
+                        *cell = Cell { c: ' ', fg: self.fg, bg: self.bg, bold: false };
+                    }
+                }
+            }
+            '\x1b' => self.escape = Escape::Start,
             c => {
-                match get_raster(c, FontWeight::Regular, Size16) {
-                    Some(bitmap_char) => {
-                        if self.x_pos + bitmap_char.width() > self.width() {
-                            self.newline();
-                        }
-                        if self.y_pos + bitmap_char.height() > self.height() {
-                            self.clear();
+                if self.x_pos + CHAR_WIDTH > self.width() {
+                    self.newline();
+                }
+                let cell = Cell { c, fg: self.fg, bg: self.bg, bold: self.bold };
+                if self.draw_cell(self.x_pos, self.y_pos, cell) {
+                    self.record(cell);
+                    self.x_pos += CHAR_WIDTH;
+                }
+            }
+        }
+    }
+
+    /// Acts on a complete `ESC [ params final` sequence. Supports SGR (`m`) colours and
+    /// bold, and erase display (`2J`); everything else is ignored.
+    fn handle_csi(&mut self, command: char, params: &[u16]) {
+        match command {
+            'm' => {
+                for &param in params {
+                    match param {
+                        0 => {
+                            self.fg = DEFAULT_FG;
+                            self.bg = DEFAULT_BG;
+                            self.bold = false;
                         }
-                        self.write_rendered_char(bitmap_char);
-                    },
-                    None => {}
+                        1 => self.bold = true,
+                        22 => self.bold = false,
+                        30..=37 => self.fg = ANSI_COLORS[param as usize - 30],
+                        39 => self.fg = DEFAULT_FG,
+                        40..=47 => self.bg = ANSI_COLORS[param as usize - 40],
+                        49 => self.bg = DEFAULT_BG,
+                        90..=97 => self.fg = ANSI_COLORS[param as usize - 90 + 8],
+                        100..=107 => self.bg = ANSI_COLORS[param as usize - 100 + 8],
+                        _ => {}
+                    }
                 }
             }
+            'J' if params == [2] => self.clear(),
+            _ => {}
         }
     }
 
-    fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
-        for (y, row) in rendered_char.raster().iter().enumerate() {
-            for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(self.x_pos + x, self.y_pos + y, *byte);
+    /// Draws one console character cell. Returns false if the font has no glyph for it.
+    fn draw_cell(&mut self, x: usize, y: usize, cell: Cell) -> bool {
+        let weight = if cell.bold { FontWeight::Bold } else { FontWeight::Regular };
+        match get_raster(cell.c, weight, Size16) {
+            Some(raster) => {
+                self.draw_glyph(x, y, &raster, cell.fg, Some(cell.bg));
+                true
             }
+            None => false,
+        }
+    }
+
+    /// Stores a written cell in the current scrollback line at the cursor's column.
+    fn record(&mut self, cell: Cell) {
+        if self.scrollback_lines == 0 {
+            return;
+        }
+        let column = self.x_pos / CHAR_WIDTH;
+        let blank = Cell { c: ' ', ..cell };
+        if self.current_line.len() <= column {
+            self.current_line.resize(column + 1, blank);
         }
-        self.x_pos += rendered_char.width();
+        self.current_line[column] = cell;
     }
 
+    /// Moves the current line into the scrollback, dropping the oldest line if it is full.
+    fn end_line(&mut self) {
+        if self.scrollback_lines == 0 {
+            return;
+        }
+        if self.scrollback.len() == self.scrollback_lines {
+            self.scrollback.pop_front();
+        }
+        self.scrollback.push_back(mem::take(&mut self.current_line));
+    }
+
+    #[allow(dead_code)]
     pub fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
         // Check if coordinates are within screen bounds
         if x >= self.width() || y >= self.height() {
             return; // Skip drawing if coordinates are out of bounds
         }
         
-        let pixel_offset = y * usize::from(self.info.stride) + x;
-        let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [intensity / 4, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity / 4, 0],
-            other => {
-                // set a supported (but invalid) pixel format before panicking to avoid a double
-                // panic; it might not be readable though
-                self.info.pixel_format = PixelFormat::Rgb;
-                panic!("pixel format {:?} not supported in logger", other)
-            }
-        };
-        let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * usize::from(bytes_per_pixel);
-        
-        // Add an additional bounds check on the calculated byte_offset
-        if byte_offset + usize::from(bytes_per_pixel) > self.framebuffer.len() {
-            return; // Prevent buffer overflow
-        }
-        
-        self.framebuffer[byte_offset..(byte_offset + usize::from(bytes_per_pixel))]
-            .copy_from_slice(&color[..usize::from(bytes_per_pixel)]);
-        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
+        let color = self.encoder.encode((intensity / 4, intensity, intensity / 2));
+        self.put_pixel(x, y, color);
     }
 
+    #[allow(dead_code)]
     pub fn draw_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8) {
         // Check if coordinates are within screen bounds
         if x >= self.width() || y >= self.height() {
             return; // Skip drawing if coordinates are out of bounds
         }
         
-        let pixel_offset = y * usize::from(self.info.stride) + x;
-        let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [r, g, b, 0],
-            PixelFormat::Bgr => [b, g, r, 0],
-            other => {
-                // set a supported (but invalid) pixel format before panicking to avoid a double
-                // panic; it might not be readable though
-                self.info.pixel_format = PixelFormat::Rgb;
-                panic!("pixel format {:?} not supported in logger", other)
+        let color = self.encoder.encode((r, g, b));
+        self.put_pixel(x, y, color);
+    }
+
+    /// Fills the rectangle with its top-left corner at (x, y). Parts outside the screen are
+    /// clipped.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        let x_end = x.saturating_add(width).min(self.width());
+        let y_end = y.saturating_add(height).min(self.height());
+        if x >= x_end || y >= y_end {
+            return;
+        }
+
+        let pixel = self.encoder.encode(color);
+        for row in y..y_end {
+            self.fill_span(x, x_end, row, pixel);
+        }
+        self.mark_dirty(y, y_end - 1);
+    }
+
+    /// Draws the one pixel wide outline of a rectangle.
+    #[allow(dead_code)]
+    pub fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        self.fill_rect(x, y, width, 1, color);
+        self.fill_rect(x, y + height - 1, width, 1, color);
+        self.fill_rect(x, y, 1, height, color);
+        self.fill_rect(x + width - 1, y, 1, height, color);
+    }
+
+    /// Draws a line between two points using Bresenham's algorithm.
+    pub fn draw_line(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, color: Color) {
+        if y0 == y1 {
+            self.fill_rect(x0.min(x1), y0, x0.abs_diff(x1) + 1, 1, color);
+            return;
+        }
+
+        let pixel = self.encoder.encode(color);
+        let (mut x, mut y) = (x0 as isize, y0 as isize);
+        let (x1, y1) = (x1 as isize, y1 as isize);
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let step_x = if x < x1 { 1 } else { -1 };
+        let step_y = if y < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            if (x as usize) < self.width() && (y as usize) < self.height() {
+                self.put_pixel(x as usize, y as usize, pixel);
             }
-        };
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Fills a circle around (center_x, center_y), one horizontal span per row.
+    pub fn fill_circle(&mut self, center_x: usize, center_y: usize, radius: usize, color: Color) {
+        let radius_squared = radius * radius;
+        for dy in 0..=radius {
+            // Widest dx with dx² + dy² <= r²
+            let mut dx = radius;
+            while dx * dx + dy * dy > radius_squared {
+                dx -= 1;
+            }
+            let x = center_x.saturating_sub(dx);
+            let width = center_x + dx + 1 - x;
+            self.fill_rect(x, center_y + dy, width, 1, color);
+            if dy > 0 && center_y >= dy {
+                self.fill_rect(x, center_y - dy, width, 1, color);
+            }
+        }
+    }
+
+    /// Copies an in-memory image to (x, y). `pixels` holds `width * height` RGB triples,
+    /// row by row. Parts outside the screen are clipped.
+    #[allow(dead_code)]
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[u8]) {
+        assert!(pixels.len() >= width * height * 3, "image data too short");
+        let x_end = x.saturating_add(width).min(self.width());
+        let y_end = y.saturating_add(height).min(self.height());
+        if x >= x_end || y >= y_end {
+            return;
+        }
+
         let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * usize::from(bytes_per_pixel);
-        
+        let stride = self.info.stride;
+        for row in y..y_end {
+            let source = &pixels[(row - y) * width * 3..][..(x_end - x) * 3];
+            let start = (row * stride + x) * bytes_per_pixel;
+            for (i, rgb) in source.chunks_exact(3).enumerate() {
+                let pixel = self.encoder.encode((rgb[0], rgb[1], rgb[2]));
+                let offset = start + i * bytes_per_pixel;
+                store_pixel(&mut self.buffer_mut()[offset..offset + bytes_per_pixel], pixel);
+            }
+        }
+        self.mark_dirty(y, y_end - 1);
+    }
+
+    /// Draws `text` with its top-left corner at (x, y), independent of the text cursor used by
+    /// `fmt::Write`. Each glyph's anti-aliasing is blended from `bg` to `fg`; with `bg` set to
+    /// `None` only the glyph itself is drawn and the background is left alone. A `'\n'` starts
+    /// a new line below at the same `x`. Characters missing from the font are skipped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, fg: Color, bg: Option<Color>,
+                     weight: FontWeight, size: RasterHeight) {
+        let char_width = get_raster_width(weight, size);
+        let line_height = size as usize + LINE_SPACING;
+
+        for (line_index, line) in text.split('\n').enumerate() {
+            let line_y = y + line_index * line_height;
+            for (char_index, c) in line.chars().enumerate() {
+                if let Some(raster) = get_raster(c, weight, size) {
+                    self.draw_glyph(x + char_index * char_width, line_y, &raster, fg, bg);
+                }
+            }
+        }
+    }
+
+    /// Replaces the whole screen with a "blue screen" showing `report`, and presents it
+    /// straight away. Does not allocate, so it is safe to call from a panic.
+    pub fn draw_panic_page(&mut self, report: &str) {
+        let title = "Kernel panic";
+        let (_, title_height) = Self::measure_text(title, FontWeight::Bold, RasterHeight::Size32);
+
+        self.fill_rect(0, 0, self.width(), self.height(), PANIC_BG);
+        self.draw_text(PANIC_MARGIN, PANIC_MARGIN, title, PANIC_FG, Some(PANIC_BG),
+                       FontWeight::Bold, RasterHeight::Size32);
+        self.draw_text(PANIC_MARGIN, PANIC_MARGIN + title_height + LINE_HEIGHT, report, PANIC_FG,
+                       Some(PANIC_BG), FontWeight::Regular, Size16);
+        self.present();
+    }
+
+    /// Returns the width and height in pixels that `draw_text` would cover for `text`, e.g.
+    /// to centre it with `(screen_width - width) / 2`.
+    pub fn measure_text(text: &str, weight: FontWeight, size: RasterHeight) -> (usize, usize) {
+        let columns = text.split('\n').map(|line| line.chars().count()).max().unwrap_or(0);
+        let lines = text.split('\n').count();
+        (columns * get_raster_width(weight, size), lines * (size as usize + LINE_SPACING))
+    }
+
+    fn draw_glyph(&mut self, x: usize, y: usize, raster: &RasterizedChar, fg: Color, bg: Option<Color>) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let columns = raster.width().min(self.width() - x);
+        let rows = raster.height().min(self.height() - y);
+
+        for (dy, row) in raster.raster().iter().take(rows).enumerate() {
+            for (dx, &intensity) in row.iter().take(columns).enumerate() {
+                let color = match bg {
+                    Some(bg) => blend(bg, fg, intensity),
+                    None if intensity == 0 => continue,
+                    None => blend((0, 0, 0), fg, intensity),
+                };
+                let pixel = self.encoder.encode(color);
+                self.put_pixel(x + dx, y + dy, pixel);
+            }
+        }
+    }
+
+    /// Writes the encoded pixel to every column in `x..x_end` of `row`. The span must
+    /// already be clipped to the screen.
+    fn fill_span(&mut self, x: usize, x_end: usize, row: usize, pixel: [u8; 4]) {
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let start = (row * self.info.stride + x) * bytes_per_pixel;
+        let end = (row * self.info.stride + x_end) * bytes_per_pixel;
+        for target in self.buffer_mut()[start..end].chunks_exact_mut(bytes_per_pixel) {
+            store_pixel(target, pixel);
+        }
+    }
+
+    /// Stores an already encoded pixel in the current draw buffer.
+    fn put_pixel(&mut self, x: usize, y: usize, color: [u8; 4]) {
+        let pixel_offset = y * self.info.stride + x;
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let byte_offset = pixel_offset * bytes_per_pixel;
+        let double_buffered = self.back_buffer.is_some();
+        let buffer = self.buffer_mut();
+
         // Add an additional bounds check on the calculated byte_offset
-        if byte_offset + usize::from(bytes_per_pixel) > self.framebuffer.len() {
+        if byte_offset + bytes_per_pixel > buffer.len() {
             return; // Prevent buffer overflow
         }
-        
-        self.framebuffer[byte_offset..(byte_offset + usize::from(bytes_per_pixel))]
-            .copy_from_slice(&color[..usize::from(bytes_per_pixel)]);
-        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
+
+        store_pixel(&mut buffer[byte_offset..(byte_offset + bytes_per_pixel)], color);
+        if double_buffered {
+            self.mark_dirty(y, y);
+        } else {
+            let _ = unsafe { ptr::read_volatile(&buffer[byte_offset]) };
+        }
     }
+}
+
+/// Copies an encoded pixel into the `bytes_per_pixel` bytes of `target`. Bytes past the
+/// four colour bytes are padding and left alone.
+fn store_pixel(target: &mut [u8], pixel: [u8; 4]) {
+    let len = target.len().min(pixel.len());
+    target[..len].copy_from_slice(&pixel[..len]);
+}
 
+/// Mixes `from` and `to`, where an intensity of 0 gives `from` and 255 gives `to`.
+fn blend(from: Color, to: Color, intensity: u8) -> Color {
+    let mix = |a: u8, b: u8| ((a as u16 * (255 - intensity as u16) + b as u16 * intensity as u16) / 255) as u8;
+    (mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
 }
 
 unsafe impl Send for ScreenWriter {}
@@ -0,0 +1,177 @@
+//! A small line-based debug shell on the serial port.
+//!
+//! Bytes received over serial are fed to [Shell::handle_byte], which echoes them and runs the line
+//! when Enter is pressed. Output goes back over serial, so the shell keeps working even when
+//! the screen is busy drawing Pong. Type `help` for the list of commands.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader_api::info::{MemoryRegion, MemoryRegions};
+use core::fmt::Write;
+use kernel::interrupts::{self, APICOffset};
+use kernel::{io_apic, smp};
+use kernel::serial;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+use crate::pong::PongGame;
+use crate::{allocator, frame_allocator};
+
+const PROMPT: &str = "> ";
+const MAX_LINE_LENGTH: usize = 128;
+
+/// The shell's state: the line being typed and what the commands work on.
+pub struct Shell {
+    line: String,
+    /// Copy of the bootloader's memory map, for `mem`
+    memory_map: Vec<MemoryRegion>,
+    game: Arc<Mutex<PongGame>>,
+}
+
+impl Shell {
+    /// Creates a shell controlling `game`. Needs the heap.
+    pub fn new(memory_map: &MemoryRegions, game: Arc<Mutex<PongGame>>) -> Self {
+        Self { line: String::new(), memory_map: memory_map.iter().copied().collect(), game }
+    }
+
+    /// Handles one byte received over serial: echoes it, edits the line and runs it on Enter.
+    pub fn handle_byte(&mut self, byte: u8) {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = core::mem::take(&mut self.line);
+                let _ = writeln!(serial());
+                self.run(line.trim());
+                prompt();
+            }
+            // Backspace and delete both erase the last character
+            0x08 | 0x7F => {
+                if self.line.pop().is_some() {
+                    let _ = write!(serial(), "\x08 \x08");
+                }
+            }
+            0x20..=0x7E => {
+                if self.line.len() < MAX_LINE_LENGTH {
+                    self.line.push(byte as char);
+                    let _ = write!(serial(), "{}", byte as char);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn run(&mut self, line: &str) {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return;
+        };
+
+        let result = match (command, words.next(), words.next()) {
+            ("help", None, _) => help(),
+            ("mem", None, _) => self.mem(),
+            ("apic", None, _) => apic(),
+            ("ioapic", None, _) => io_apic::dump(&mut serial()),
+            ("idt", None, _) => interrupts::dump_idt(&mut serial()),
+            ("cpus", None, _) => cpus(),
+            ("hello", Some(cpu), None) => match cpu.parse::<usize>() {
+                Ok(cpu) if smp::run_on(cpu, move || {
+                    let _ = writeln!(serial(), "Hello from CPU {} ({} ticks)", smp::cpu_index(),
+                                     smp::current().map_or(0, |cpu| cpu.ticks()));
+                }) => Ok(()),
+                _ => writeln!(serial(), "No CPU {}", cpu),
+            },
+            ("ticks", None, _) => writeln!(serial(), "{} ticks at {} Hz, up {:?}",
+                                           kernel::ticks(), interrupts::timer_frequency(), kernel::uptime()),
+            ("pong", Some("reset"), None) => {
+                self.game.lock().new_game();
+                writeln!(serial(), "New game started")
+            }
+            ("pong", Some("speed"), Some(speed)) => match speed.parse::<usize>() {
+                Ok(speed) if speed > 0 => {
+                    self.game.lock().set_ball_speed(speed);
+                    writeln!(serial(), "Ball speed set to {}", speed)
+                }
+                _ => writeln!(serial(), "Speed must be a positive number"),
+            },
+            ("reboot", None, _) => reboot(),
+            _ => writeln!(serial(), "Unknown command: {} (try 'help')", line),
+        };
+        result.unwrap();
+    }
+
+    fn mem(&self) -> core::fmt::Result {
+        let mut out = serial();
+        writeln!(out, "Memory map:")?;
+        for region in &self.memory_map {
+            writeln!(out, "  {:#012x}-{:#012x} {:>10} KiB {:?}",
+                     region.start, region.end, (region.end - region.start) / 1024, region.kind)?;
+        }
+        match frame_allocator::stats() {
+            Some(stats) => writeln!(out, "Physical memory: {}", stats)?,
+            None => writeln!(out, "Physical memory: frame allocator not initialized")?,
+        }
+        writeln!(out, "Heap statistics:\n{}", allocator::stats())
+    }
+}
+
+/// Prints the prompt.
+pub fn prompt() {
+    let _ = write!(serial(), "{}", PROMPT);
+}
+
+fn help() -> core::fmt::Result {
+    writeln!(serial(), "Commands:")?;
+    writeln!(serial(), "  mem           memory map, frame and heap statistics")?;
+    writeln!(serial(), "  apic          local APIC registers")?;
+    writeln!(serial(), "  ioapic        IO APICs and their unmasked lines")?;
+    writeln!(serial(), "  idt           present IDT entries")?;
+    writeln!(serial(), "  cpus          running CPUs and their timer ticks")?;
+    writeln!(serial(), "  hello N       greet from CPU N")?;
+    writeln!(serial(), "  ticks         timer ticks and uptime")?;
+    writeln!(serial(), "  pong reset    start a new game")?;
+    writeln!(serial(), "  pong speed N  serve the ball at N pixels per step")?;
+    writeln!(serial(), "  reboot        restart the machine")
+}
+
+fn apic() -> core::fmt::Result {
+    let mut out = serial();
+    let lapic = &interrupts::LAPIC_ADDR;
+    if !lapic.is_present() {
+        return writeln!(out, "Local APIC not set up");
+    }
+    writeln!(out, "Local APIC in {} mode", if lapic.is_x2apic() { "x2APIC" } else { "xAPIC" })?;
+    for offset in APICOffset::READABLE {
+        // Registers missing in x2APIC mode are skipped
+        if let Some(value) = interrupts::read_lapic(offset) {
+            writeln!(out, "{:<8} ({:#05x}) = {:#010x}", alloc::format!("{:?}", offset), offset as isize, value)?;
+        }
+    }
+    Ok(())
+}
+
+fn cpus() -> core::fmt::Result {
+    let mut out = serial();
+    for cpu in (0..smp::cpu_count()).filter_map(smp::cpu) {
+        writeln!(out, "CPU {}: APIC ID {}, {} ticks", cpu.index(), cpu.apic_id(), cpu.ticks())?;
+    }
+    Ok(())
+}
+
+fn reboot() -> ! {
+    let _ = writeln!(serial(), "Rebooting...");
+    x86_64::instructions::interrupts::disable();
+
+    // Pulse the CPU reset line through the keyboard controller
+    unsafe { Port::<u8>::new(0x64).write(0xFE) };
+
+    // If that did not work, load an empty IDT and raise an exception to triple fault
+    let empty_idt = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty_idt);
+        core::arch::asm!("int3");
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
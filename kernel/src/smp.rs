@@ -0,0 +1,445 @@
+//! Symmetric multiprocessing: starting the application processors (APs) the MADT lists, data
+//! of each CPU, and running work on a chosen CPU.
+//!
+//! [init] allocates the tables and per-CPU data of each AP, then
+//! [start_application_processors] copies a small piece of start-up code into a frame below
+//! 1 MiB and wakes each AP with the INIT-SIPI-SIPI sequence. The code switches the AP from
+//! real mode straight to long mode on the kernel's page tables and calls `ap_main` on the AP's
+//! own stack, which loads the AP's GDT, TSS and IDT, starts its LAPIC timer and then runs the
+//! work queued for it:
+//!
+//! ```ignore
+//! kernel::smp::run_on(2, || {
+//!     let _ = writeln!(kernel::serial(), "Hello from CPU {}", kernel::smp::cpu_index());
+//! });
+//! ```
+//!
+//! Each CPU finds its [PerCpu] through its GS base. Device interrupts, the uptime and the
+//! events of the [crate::HandlerTable] all stay on the bootstrap processor (CPU 0); the other
+//! CPUs only count their own timer ticks.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use acpi::platform::{Processor, ProcessorState};
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, GsBase};
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use crate::gdt::{self, CpuTables};
+use crate::interrupts::{self, LAPIC_ADDR};
+use crate::time::Duration;
+
+/// INIT IPI: INIT delivery mode, level assert
+const INIT_IPI: u32 = 0x4500;
+/// Start-up IPI: start-up delivery mode, level assert. The vector is the page of the code.
+const STARTUP_IPI: u32 = 0x4600;
+/// How long an AP gets to reach `ap_main`
+const AP_START_TIMEOUT: Duration = Duration::from_millis(100);
+/// How often the bootstrap processor checks whether the AP got there
+const AP_START_POLL: Duration = Duration::from_micros(100);
+
+/// Layout of the start-up code: a jump over the data the bootstrap processor fills in, then
+/// the code. Offsets from its start.
+const GDT_OFFSET: usize = 0x08;
+const GDT_POINTER_OFFSET: usize = 0x20;
+const LONG_MODE_TARGET_OFFSET: usize = 0x28;
+const CR0_OFFSET: usize = 0x30;
+const CR3_OFFSET: usize = 0x38;
+const CR4_OFFSET: usize = 0x40;
+const EFER_OFFSET: usize = 0x48;
+const STACK_OFFSET: usize = 0x50;
+const ENTRY_OFFSET: usize = 0x58;
+const ARGUMENT_OFFSET: usize = 0x60;
+const CODE_OFFSET: usize = 0x68;
+
+// Real-mode start-up code, run by each AP from the start of a page below 1 MiB. It does not
+// know which page that is: CS points at it, so the 16-bit part addresses its data relative to
+// DS = CS, and [init] fills in the linear addresses of the GDT and the 64-bit part. The
+// 64-bit part addresses its data relative to RIP.
+core::arch::global_asm!(
+    ".section .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "    jmp ap_trampoline_real_mode",
+    ".org {gdt}",
+    "    .quad 0",
+    "    .quad 0x00AF9A000000FFFF", // 64-bit code
+    "    .quad 0x00CF92000000FFFF", // Data
+    ".org {gdt_pointer}",
+    "    .word 23",
+    "    .long 0",
+    ".org {long_mode_target}",
+    "    .long 0",
+    "    .word 0x08",
+    ".org {cr0}",
+    "    .quad 0, 0, 0, 0, 0, 0, 0", // CR0, CR3, CR4, EFER, stack, entry, argument
+    ".org {code}",
+    "ap_trampoline_real_mode:",
+    "    cli",
+    "    cld",
+    "    mov ax, cs",
+    "    mov ds, ax",
+    "    lgdt [{gdt_pointer}]",
+    "    mov eax, dword ptr [{cr4}]",
+    "    mov cr4, eax",
+    "    mov eax, dword ptr [{cr3}]",
+    "    mov cr3, eax",
+    "    mov ecx, 0xC0000080", // IA32_EFER, enables long mode
+    "    mov eax, dword ptr [{efer}]",
+    "    xor edx, edx",
+    "    wrmsr",
+    // Protection and paging on at once, which activates long mode
+    "    mov eax, dword ptr [{cr0}]",
+    "    mov cr0, eax",
+    // jmp far dword ptr [long_mode_target], into the 64-bit code segment
+    "    .byte 0x66, 0xFF, 0x2E",
+    "    .word {long_mode_target}",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov rsp, qword ptr [rip + ap_trampoline_start + {stack}]",
+    "    mov rdi, qword ptr [rip + ap_trampoline_start + {argument}]",
+    "    call qword ptr [rip + ap_trampoline_start + {entry}]",
+    "    ud2",
+    "ap_trampoline_end:",
+    ".text",
+    gdt = const GDT_OFFSET,
+    gdt_pointer = const GDT_POINTER_OFFSET,
+    long_mode_target = const LONG_MODE_TARGET_OFFSET,
+    cr0 = const CR0_OFFSET,
+    cr3 = const CR3_OFFSET,
+    cr4 = const CR4_OFFSET,
+    efer = const EFER_OFFSET,
+    stack = const STACK_OFFSET,
+    entry = const ENTRY_OFFSET,
+    argument = const ARGUMENT_OFFSET,
+    code = const CODE_OFFSET,
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_end: u8;
+}
+
+type Work = Box<dyn FnOnce() + Send>;
+
+/// The data of one CPU. Each CPU's GS base points at its own.
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this structure, so `gs:[0]` yields it
+    self_address: u64,
+    index: usize,
+    apic_id: u32,
+    ticks: AtomicU64,
+    work: Mutex<VecDeque<Work>>,
+}
+
+impl PerCpu {
+    fn new(index: usize, apic_id: u32) -> &'static PerCpu {
+        let cpu = Box::leak(Box::new(PerCpu {
+            self_address: 0,
+            index,
+            apic_id,
+            ticks: AtomicU64::new(0),
+            work: Mutex::new(VecDeque::new()),
+        }));
+        cpu.self_address = cpu as *const PerCpu as u64;
+        cpu
+    }
+
+    /// The CPU's number, 0 being the bootstrap processor.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The ID of the CPU's local APIC.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// Number of timer interrupts the CPU has handled.
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Points the running CPU's GS base at this structure.
+    fn make_current(&'static self) {
+        GsBase::write(VirtAddr::new(self.self_address));
+    }
+}
+
+/// What `ap_main` needs, prepared by [init] so neither the AP nor the start-up allocates.
+struct ApStart {
+    cpu: &'static PerCpu,
+    tables: &'static CpuTables,
+    idt: &'static InterruptDescriptorTable,
+}
+
+/// Every running CPU, by index.
+static CPUS: Mutex<Vec<&'static PerCpu>> = Mutex::new(Vec::new());
+/// Set once the bootstrap processor's GS base points at its [PerCpu].
+static PER_CPU_READY: AtomicBool = AtomicBool::new(false);
+/// APIC IDs of the APs waiting to be started, from the MADT.
+static APPLICATION_PROCESSORS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+/// The prepared APs, in the order of their index.
+static AP_STARTS: Mutex<Vec<&'static ApStart>> = Mutex::new(Vec::new());
+/// Set by an AP once it has reached `ap_main` and set itself up.
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Remembers the APs of the MADT that can be started.
+pub(crate) fn set_application_processors(processors: &[Processor]) {
+    *APPLICATION_PROCESSORS.lock() = processors.iter()
+        .filter(|processor| processor.state == ProcessorState::WaitingForSipi)
+        .map(|processor| processor.local_apic_id)
+        .collect();
+}
+
+/// Sets up the per-CPU data of the bootstrap processor, and allocates the tables and per-CPU
+/// data of the APs listed in the MADT (about 5 KiB of heap each). Call after
+/// [interrupts::init_interrupt_controller], while interrupts are still disabled, and with the
+/// page tables unlocked so the heap can grow.
+pub fn init() {
+    let bsp = PerCpu::new(0, interrupts::local_apic_id());
+    bsp.make_current();
+    let processors = core::mem::take(&mut *APPLICATION_PROCESSORS.lock());
+    {
+        let mut cpus = CPUS.lock();
+        // Starting the APs must not allocate, see [start_application_processors]
+        cpus.reserve(processors.len() + 1);
+        cpus.push(bsp);
+    }
+    PER_CPU_READY.store(true, Ordering::Release);
+
+    *AP_STARTS.lock() = processors.into_iter().enumerate().map(|(i, apic_id)| {
+        let index = i + 1;
+        &*Box::leak(Box::new(ApStart {
+            cpu: PerCpu::new(index, apic_id),
+            tables: CpuTables::new(index),
+            idt: Box::leak(Box::new(interrupts::new_idt())),
+        }))
+    }).collect();
+}
+
+/// Starts the APs [init] prepared, one after the other, from `trampoline`, a frame below 1 MiB
+/// (without one, only the bootstrap processor runs). Maps their stacks but does not allocate,
+/// so it can run with the page tables locked.
+pub fn start_application_processors(
+    trampoline: Option<PhysFrame>,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let starts = core::mem::take(&mut *AP_STARTS.lock());
+    if !starts.is_empty() {
+        let installed = trampoline.ok_or("no free frame below 1 MiB").and_then(|trampoline| {
+            install_trampoline(trampoline, mapper, frame_allocator).map(|()| trampoline)
+        });
+        match installed {
+            Ok(trampoline) => {
+                for start in starts {
+                    match start_ap(start, trampoline, mapper, frame_allocator) {
+                        Ok(()) => CPUS.lock().push(start.cpu),
+                        Err(reason) => {
+                            // A late AP could still run on the stacks of this index, so stop here
+                            warn!("CPU {} (APIC ID {}) did not start: {}", start.cpu.index, start.cpu.apic_id, reason);
+                            break;
+                        }
+                    }
+                }
+                if let Ok((_, flush)) = mapper.unmap(Page::<Size4KiB>::containing_address(VirtAddr::new(trampoline.start_address().as_u64()))) {
+                    flush.flush();
+                }
+            }
+            Err(reason) => warn!("Not starting the application processors: {}", reason),
+        }
+    }
+    info!("Running on {} CPU(s)", cpu_count());
+}
+
+/// Offset of a symbol of the start-up code from its start.
+fn trampoline_offset(symbol: *const u8) -> usize {
+    symbol as usize - (&raw const ap_trampoline_start) as usize
+}
+
+/// Writes `value` at `offset` in the copy of the start-up code at `base`.
+unsafe fn patch<T>(base: *mut u8, offset: usize, value: T) {
+    unsafe { base.add(offset).cast::<T>().write_unaligned(value) };
+}
+
+/// Identity maps `frame`, copies the start-up code there and fills in what is the same for
+/// every AP.
+fn install_trampoline(
+    frame: PhysFrame,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let base_address = frame.start_address().as_u64();
+    if base_address >= 0x10_0000 {
+        return Err("the start-up code must lie below 1 MiB");
+    }
+    // The AP loads CR3 while still in real mode, so only 32 bits of it
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    if cr3 > u32::MAX as u64 {
+        return Err("the page tables lie above 4 GiB");
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.identity_map(frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(_) => return Err("cannot identity map the start-up code"),
+    }
+
+    let base = base_address as *mut u8;
+    let size = trampoline_offset(&raw const ap_trampoline_end);
+    unsafe {
+        core::ptr::copy_nonoverlapping(&raw const ap_trampoline_start, base, size);
+
+        // The limit comes first, then the base
+        patch(base, GDT_POINTER_OFFSET + 2, base_address as u32 + GDT_OFFSET as u32);
+        patch(base, LONG_MODE_TARGET_OFFSET, base_address as u32 + trampoline_offset(&raw const ap_trampoline_long_mode) as u32);
+        // The control registers of the bootstrap processor, less what cannot be set yet
+        patch(base, CR0_OFFSET, Cr0::read_raw());
+        patch(base, CR3_OFFSET, cr3);
+        patch(base, CR4_OFFSET, (Cr4::read() - Cr4Flags::PCID).bits());
+        patch(base, EFER_OFFSET, Efer::read_raw() & !(1 << 10)); // LMA is read-only
+        patch(base, ENTRY_OFFSET, ap_main as usize as u64);
+    }
+    Ok(())
+}
+
+/// Maps the stacks of the AP `start` is for and starts it with INIT-SIPI-SIPI.
+fn start_ap(
+    start: &'static ApStart,
+    trampoline: PhysFrame,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let (index, apic_id) = (start.cpu.index, start.cpu.apic_id);
+    gdt::map_ist_stacks(index, mapper, frame_allocator).map_err(|_| "cannot map its IST stacks")?;
+    let stack_top = gdt::map_ap_stack(index, mapper, frame_allocator).map_err(|_| "cannot map its kernel stack")?;
+
+    let base = trampoline.start_address().as_u64() as *mut u8;
+    unsafe {
+        patch(base, STACK_OFFSET, stack_top.as_u64());
+        patch(base, ARGUMENT_OFFSET, start as *const ApStart as u64);
+    }
+    AP_STARTED.store(false, Ordering::Release);
+
+    // Interrupts are off and the TSC may not be usable yet, so the waits are timed by the PIT
+    let startup_ipi = STARTUP_IPI | (trampoline.start_address().as_u64() >> 12) as u32;
+    LAPIC_ADDR.send_ipi(apic_id, INIT_IPI);
+    interrupts::pit_delay(Duration::from_millis(10));
+    LAPIC_ADDR.send_ipi(apic_id, startup_ipi);
+    interrupts::pit_delay(Duration::from_micros(200));
+    if !AP_STARTED.load(Ordering::Acquire) {
+        LAPIC_ADDR.send_ipi(apic_id, startup_ipi);
+    }
+
+    let mut waited = Duration::ZERO;
+    while !AP_STARTED.load(Ordering::Acquire) {
+        if waited >= AP_START_TIMEOUT {
+            return Err("timed out");
+        }
+        interrupts::pit_delay(AP_START_POLL);
+        waited += AP_START_POLL;
+    }
+    info!("CPU {} (APIC ID {}) started", index, apic_id);
+    Ok(())
+}
+
+/// Where the start-up code leaves an AP, on its own stack.
+extern "C" fn ap_main(start: &'static ApStart) -> ! {
+    start.tables.load();
+    start.idt.load();
+    start.cpu.make_current();
+    interrupts::init_ap_local_apic();
+    AP_STARTED.store(true, Ordering::Release);
+
+    ap_loop()
+}
+
+/// The CPU loop of the APs: runs the queued work, then halts until the next interrupt.
+fn ap_loop() -> ! {
+    loop {
+        run_pending_work();
+
+        // As in crate::event_loop, `sti; hlt` cannot miss work queued after the check
+        x86_64::instructions::interrupts::disable();
+        if has_pending_work() {
+            x86_64::instructions::interrupts::enable();
+        } else {
+            x86_64::instructions::interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// The running CPU's data, or None before [init].
+pub fn current() -> Option<&'static PerCpu> {
+    if !PER_CPU_READY.load(Ordering::Acquire) {
+        return None;
+    }
+    let address: u64;
+    unsafe { core::arch::asm!("mov {}, gs:[0]", out(reg) address, options(nostack, readonly, preserves_flags)) };
+    Some(unsafe { &*(address as *const PerCpu) })
+}
+
+/// The running CPU's number, 0 being the bootstrap processor.
+pub fn cpu_index() -> usize {
+    current().map_or(0, PerCpu::index)
+}
+
+/// Number of running CPUs.
+pub fn cpu_count() -> usize {
+    without_interrupts(|| CPUS.lock().len()).max(1)
+}
+
+/// The data of CPU `index`, if it is running.
+pub fn cpu(index: usize) -> Option<&'static PerCpu> {
+    without_interrupts(|| CPUS.lock().get(index).copied())
+}
+
+/// Runs `work` on CPU `index`, from its CPU loop with interrupts enabled. Returns false if
+/// there is no such CPU. On the bootstrap processor the work runs from [crate::event_loop], so
+/// a custom CPU loop has to call [run_pending_work] itself.
+pub fn run_on(index: usize, work: impl FnOnce() + Send + 'static) -> bool {
+    let Some(target) = cpu(index) else {
+        return false;
+    };
+    without_interrupts(|| target.work.lock().push_back(Box::new(work)));
+    if index != cpu_index() {
+        interrupts::send_wakeup(target.apic_id);
+    }
+    true
+}
+
+/// Runs the work queued for the running CPU.
+pub fn run_pending_work() {
+    let Some(cpu) = current() else {
+        return;
+    };
+    // Take one item at a time, so the work may queue more
+    while let Some(work) = without_interrupts(|| cpu.work.lock().pop_front()) {
+        work();
+    }
+}
+
+/// Whether work is queued for the running CPU.
+pub fn has_pending_work() -> bool {
+    current().is_some_and(|cpu| without_interrupts(|| !cpu.work.lock().is_empty()))
+}
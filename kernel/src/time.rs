@@ -0,0 +1,178 @@
+//! High-resolution time keeping.
+//!
+//! When the CPU has an invariant TSC (one that ticks at a constant rate regardless of power
+//! states), it is calibrated against the PIT at boot, together with the LAPIC timer, and
+//! [Instant::now] reads it directly, giving nanosecond resolution. Otherwise [Instant] falls
+//! back to the timer tick count ([crate::uptime]), which only advances once per tick.
+//!
+//! ```ignore
+//! let start = Instant::now();
+//! sleep(Duration::from_millis(100));
+//! log::info!("Slept for {:?}", start.elapsed());
+//! ```
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub use core::time::Duration;
+
+/// TSC frequency in Hz, or 0 if there is no usable TSC.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// TSC value at the moment it was calibrated; `Instant`s count from here.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the CPU advertises an invariant TSC (CPUID leaf 0x8000_0007, EDX bit 8).
+pub fn has_invariant_tsc() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Reads the time stamp counter.
+pub fn read_tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Records the TSC frequency measured by the timer calibration. Ignored if the TSC is not
+/// invariant, since its rate could then change under us.
+pub(crate) fn calibrate_tsc(tsc_hz: u64) {
+    if tsc_hz == 0 || !has_invariant_tsc() {
+        log::info!("No invariant TSC, timing with the tick counter");
+        return;
+    }
+    TSC_START.store(read_tsc(), Ordering::Relaxed);
+    TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+    log::info!("Invariant TSC runs at {} kHz", tsc_hz / 1000);
+}
+
+/// The calibrated TSC frequency in Hz, if the TSC is used for time keeping.
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// A point in time, for measuring how long something takes. Like `std::time::Instant`, it
+/// only ever increases and has no meaning on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    /// Nanoseconds since the clock started.
+    nanos: u64,
+}
+
+impl Instant {
+    /// The current time.
+    pub fn now() -> Self {
+        let nanos = match tsc_frequency() {
+            Some(hz) => {
+                let counts = read_tsc().saturating_sub(TSC_START.load(Ordering::Relaxed));
+                (counts as u128 * 1_000_000_000 / hz as u128) as u64
+            }
+            None => crate::uptime().as_nanos() as u64,
+        };
+        Self { nanos }
+    }
+
+    /// Time passed from `earlier` to `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    /// Time passed since `self`.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    /// Time since the clock started.
+    pub fn since_start(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant { nanos: self.nanos.saturating_add(duration.as_nanos() as u64) }
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Waits for at least `duration`, halting the CPU between interrupts. The wake-ups come from
+/// the timer interrupt, so the wait ends up to one timer period late. Spins instead of halting
+/// when interrupts are disabled, as nothing would wake the CPU up.
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if x86_64::instructions::interrupts::are_enabled() {
+            x86_64::instructions::hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Measures frame times and frames per second. Call [FrameCounter::frame] once per frame.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCounter {
+    last_frame: Option<Instant>,
+    frame_time: Duration,
+    /// Start of the current one-second measurement window and frames counted in it.
+    window_start: Option<Instant>,
+    window_frames: u32,
+    fps: u32,
+}
+
+impl FrameCounter {
+    pub const fn new() -> Self {
+        Self { last_frame: None, frame_time: Duration::ZERO, window_start: None, window_frames: 0, fps: 0 }
+    }
+
+    /// Records that a frame was drawn now.
+    pub fn frame(&mut self) {
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame {
+            self.frame_time = now - last_frame;
+        }
+        self.last_frame = Some(now);
+
+        let window_start = *self.window_start.get_or_insert(now);
+        self.window_frames += 1;
+        let window = now - window_start;
+        if window >= Duration::from_secs(1) {
+            self.fps = (self.window_frames as u128 * 1_000_000_000 / window.as_nanos()) as u32;
+            self.window_start = Some(now);
+            self.window_frames = 0;
+        }
+    }
+
+    /// Time between the last two frames.
+    pub fn frame_time(&self) -> Duration {
+        self.frame_time
+    }
+
+    /// Frames per second, averaged over the last full second.
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,161 @@
+//! Software timers: one-shot and periodic callbacks at their own intervals, all driven by the
+//! single LAPIC timer interrupt.
+//!
+//! Pending timers are kept in a min-heap ordered by deadline. Whenever the CPU loop handles a
+//! timer tick the expired ones are taken off the heap and run, and periodic ones are put back
+//! with their next deadline. Callbacks therefore fire up to one timer period late; raise
+//! [crate::HandlerTable::timer_frequency] for finer resolution.
+//!
+//! ```ignore
+//! let blink = kernel::timers::every(Duration::from_millis(500), blink_cursor);
+//! kernel::timers::after(Duration::from_secs(5), || log::info!("Five seconds passed"));
+//! blink.cancel();
+//! ```
+
+use alloc::boxed::Box;
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering;
+use core::sync::atomic::{self, AtomicBool, AtomicU64};
+use spin::Mutex;
+use crate::time::{Duration, Instant};
+
+static TIMERS: Mutex<BinaryHeap<Timer>> = Mutex::new(BinaryHeap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// The timer whose callback is running (off the heap), and whether it cancelled itself.
+static RUNNING: AtomicU64 = AtomicU64::new(u64::MAX);
+static RUNNING_CANCELLED: AtomicBool = AtomicBool::new(false);
+
+struct Timer {
+    deadline: Instant,
+    /// Also breaks ties, so timers with the same deadline run in the order they were added.
+    id: u64,
+    period: Option<Duration>,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+// Reversed, so the `BinaryHeap` (a max-heap) yields the earliest deadline first.
+impl Ord for Timer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deadline, other.id).cmp(&(self.deadline, self.id))
+    }
+}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Timer {}
+
+/// Identifies a registered timer, for cancelling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle(u64);
+
+impl TimerHandle {
+    /// Stops the timer. Returns false if it had already fired (one-shot) or been cancelled.
+    pub fn cancel(self) -> bool {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            let count = timers.len();
+            timers.retain(|timer| timer.id != self.0);
+            if RUNNING.load(atomic::Ordering::Relaxed) == self.0 {
+                // A periodic timer cancelling itself from its callback
+                return !RUNNING_CANCELLED.swap(true, atomic::Ordering::Relaxed);
+            }
+            timers.len() != count
+        })
+    }
+}
+
+/// Calls `callback` once, `delay` from now.
+pub fn after(delay: Duration, callback: impl FnMut() + Send + 'static) -> TimerHandle {
+    add(Instant::now() + delay, None, Box::new(callback))
+}
+
+/// Calls `callback` every `period`, starting one period from now.
+pub fn every(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerHandle {
+    add(Instant::now() + period, Some(period), Box::new(callback))
+}
+
+fn add(deadline: Instant, period: Option<Duration>, callback: Box<dyn FnMut() + Send>) -> TimerHandle {
+    let id = NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        TIMERS.lock().push(Timer { deadline, id, period, callback });
+    });
+    TimerHandle(id)
+}
+
+/// Runs every timer whose deadline has passed. Called for every timer tick event.
+pub(crate) fn run_expired() {
+    let now = Instant::now();
+    loop {
+        // Callbacks may add or cancel timers, so do not hold the lock while they run.
+        let expired = {
+            let mut timers = TIMERS.lock();
+            match timers.peek() {
+                Some(timer) if timer.deadline <= now => timers.pop(),
+                _ => None,
+            }
+        };
+        let Some(mut timer) = expired else {
+            break;
+        };
+
+        RUNNING.store(timer.id, atomic::Ordering::Relaxed);
+        RUNNING_CANCELLED.store(false, atomic::Ordering::Relaxed);
+        (timer.callback)();
+        RUNNING.store(u64::MAX, atomic::Ordering::Relaxed);
+
+        if let Some(period) = timer.period.filter(|_| !RUNNING_CANCELLED.load(atomic::Ordering::Relaxed)) {
+            timer.deadline = next_deadline(timer.deadline, period, now);
+            TIMERS.lock().push(timer);
+        }
+    }
+}
+
+/// The deadline following `deadline` of a timer with `period`. Skips the periods that were
+/// missed rather than running them all in a burst, and is always after `now`, so that even a
+/// zero period runs once per [run_expired] instead of keeping it busy forever.
+fn next_deadline(deadline: Instant, period: Duration, now: Instant) -> Instant {
+    let next = deadline + period;
+    if next > now {
+        next
+    } else {
+        now + period.max(Duration::from_nanos(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+
+    #[test]
+    fn missed_periods_are_skipped() {
+        let start = Instant::now();
+        let period = Duration::from_millis(10);
+        assert_eq!(next_deadline(start, period, start), start + period);
+        assert_eq!(next_deadline(start, period, start + Duration::from_millis(35)), start + Duration::from_millis(45));
+    }
+
+    #[test]
+    fn zero_period_runs_once_per_pass() {
+        let runs = Arc::new(AtomicU64::new(0));
+        let counter = runs.clone();
+        let callback = Box::new(move || {
+            counter.fetch_add(1, atomic::Ordering::Relaxed);
+        });
+        // Pushed directly, as `every` disables interrupts, which a host test may not do
+        TIMERS.lock().push(Timer { deadline: Instant::now(), id: u64::MAX - 1, period: Some(Duration::ZERO), callback });
+        run_expired();
+        assert_eq!(runs.load(atomic::Ordering::Relaxed), 1);
+        TIMERS.lock().clear();
+    }
+}
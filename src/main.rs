@@ -1,11 +1,15 @@
+mod symbols;
+
+use std::process::{Command, Stdio};
 use ovmf_prebuilt::{Arch, FileType, Prebuilt, Source};
+use symbols::SymbolTable;
 
 fn main() {
     // read env variables that were set in build script
     let uefi_path = env!("UEFI_PATH");
     println!("Using image: {}", uefi_path);
 
-    let mut cmd = std::process::Command::new("qemu-system-x86_64");
+    let mut cmd = Command::new("qemu-system-x86_64");
 
     // This is the last known working version for edk2
     let edk = Source {
@@ -19,8 +23,41 @@ fn main() {
     // set kernel image
     cmd.arg("-drive").arg(format!("format=raw,file={uefi_path}"));
     cmd.arg("-serial").arg("stdio");
+    // start application processors too, see kernel::smp
+    cmd.arg("-smp").arg("4");
+
+    // symbolise backtraces against the kernel ELF the image was built from
+    let symbols = SymbolTable::load(env!("KERNEL_PATH"));
+
+    if cfg!(feature = "stack-overflow-test") {
+        run_stack_overflow_test(cmd, &symbols);
+    }
     
     // launch qemu and wait until it terminates
+    cmd.stdout(Stdio::piped());
+    let mut child = cmd.spawn().unwrap();
+    symbols::forward_serial(child.stdout.take().unwrap(), &symbols, |_| {}).unwrap();
+    child.wait().unwrap();
+}
+
+/// Runs the kernel built with `stack-overflow-test` and exits successfully once it reports a
+/// double fault on serial. A triple fault resets the machine, which `-no-reboot` turns into
+/// QEMU exiting, so the test fails instead of hanging.
+fn run_stack_overflow_test(mut cmd: Command, symbols: &SymbolTable) -> ! {
+    cmd.arg("-no-reboot").arg("-display").arg("none");
+    cmd.stdout(Stdio::piped());
     let mut child = cmd.spawn().unwrap();
+
+    let serial = child.stdout.take().unwrap();
+    symbols::forward_serial(serial, symbols, |line| {
+        if line.contains("EXCEPTION: DOUBLE FAULT") {
+            let _ = child.kill();
+            println!("stack-overflow-test: passed");
+            std::process::exit(0);
+        }
+    }).unwrap();
+
     child.wait().unwrap();
+    println!("stack-overflow-test: failed, no double fault was reported");
+    std::process::exit(1);
 }
\ No newline at end of file
@@ -0,0 +1,135 @@
+//! Adds function names to the backtraces the kernel prints on serial.
+//!
+//! Backtrace lines look like `  #3  0xffff800000123456 (kernel+0x123456)`. The `kernel+`
+//! address is the address in the kernel ELF file, which is looked up in its symbol table.
+
+use std::io::{self, Read, Write};
+
+/// Start of every backtrace line printed by the kernel.
+const BACKTRACE_MARKER: &[u8] = b"  #";
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+/// The function symbols of the kernel ELF, sorted by address.
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Reads the symbol table of the ELF file at `path`. Returns an empty table if the file
+    /// cannot be read or has no symbols, so backtraces are still shown, just without names.
+    pub fn load(path: &str) -> Self {
+        let symbols = std::fs::read(path).ok()
+            .and_then(|elf| parse_elf_symbols(&elf))
+            .unwrap_or_default();
+        if symbols.is_empty() {
+            eprintln!("No symbols found in {path}, backtraces will not be symbolised");
+        }
+        Self { symbols }
+    }
+
+    /// Returns the function containing `address` and the offset into it.
+    fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|symbol| symbol.address <= address).checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        let offset = address - symbol.address;
+        (symbol.size == 0 || offset < symbol.size).then_some((symbol.name.as_str(), offset))
+    }
+
+    /// Appends the function name to a backtrace line, if it has a `kernel+0x...` address.
+    fn annotate(&self, line: &str) -> Option<String> {
+        let start = line.find("(kernel+0x")? + "(kernel+0x".len();
+        let end = start + line[start..].find(')')?;
+        let address = u64::from_str_radix(&line[start..end], 16).ok()?;
+        // Return addresses point after the call, which may already be the next function
+        let (name, offset) = self.lookup(address.checked_sub(1)?)?;
+        Some(format!("{} {:#}+{:#x}", line.trim_end(), rustc_demangle::demangle(name), offset + 1))
+    }
+}
+
+/// Copies the kernel's serial output to stdout as it arrives. Backtrace lines are held back
+/// until complete so the function name can be added; `on_line` sees every finished line.
+pub fn forward_serial(serial: impl Read, symbols: &SymbolTable, mut on_line: impl FnMut(&str)) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let mut line = Vec::new();
+    let mut held = true;
+
+    for byte in serial.bytes() {
+        let byte = byte?;
+        line.push(byte);
+
+        if !held {
+            stdout.write_all(&[byte])?;
+        } else if !BACKTRACE_MARKER.starts_with(&line) && !line.starts_with(BACKTRACE_MARKER) {
+            held = false;
+            stdout.write_all(&line)?;
+        }
+
+        if byte == b'\n' {
+            let text = String::from_utf8_lossy(&line);
+            if held {
+                match symbols.annotate(&text) {
+                    Some(annotated) => writeln!(stdout, "{annotated}")?,
+                    None => stdout.write_all(&line)?,
+                }
+            }
+            on_line(&text);
+            line.clear();
+            held = true;
+        }
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Extracts the function symbols from a little-endian ELF64 file.
+fn parse_elf_symbols(elf: &[u8]) -> Option<Vec<Symbol>> {
+    if elf.get(..5)? != b"\x7fELF\x02" {
+        return None;
+    }
+    let section_headers = read_u64(elf, 0x28)? as usize;
+    let section_header_size = read_u16(elf, 0x3A)? as usize;
+    let section_count = read_u16(elf, 0x3C)? as usize;
+    let section = |index: usize| section_headers + index * section_header_size;
+
+    let symtab = (0..section_count).map(section).find(|&header| read_u32(elf, header + 4) == Some(SHT_SYMTAB))?;
+    let symbols_offset = read_u64(elf, symtab + 0x18)? as usize;
+    let symbols_size = read_u64(elf, symtab + 0x20)? as usize;
+    let symbol_size = read_u64(elf, symtab + 0x38)? as usize;
+    let strtab = section(read_u32(elf, symtab + 0x28)? as usize);
+    let strings = elf.get(read_u64(elf, strtab + 0x18)? as usize..)?;
+
+    let mut symbols: Vec<Symbol> = (0..symbols_size / symbol_size.max(1))
+        .map(|index| symbols_offset + index * symbol_size)
+        .filter(|&entry| elf.get(entry + 4).is_some_and(|info| info & 0xF == STT_FUNC))
+        .filter_map(|entry| {
+            let name_offset = read_u32(elf, entry)? as usize;
+            let name = strings.get(name_offset..)?.split(|&byte| byte == 0).next()?;
+            Some(Symbol {
+                address: read_u64(elf, entry + 8)?,
+                size: read_u64(elf, entry + 16)?,
+                name: String::from_utf8_lossy(name).into_owned(),
+            })
+        })
+        .collect();
+    symbols.sort_by_key(|symbol| symbol.address);
+    Some(symbols)
+}